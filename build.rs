@@ -0,0 +1,36 @@
+use std::env;
+use std::path::Path;
+
+// The libseccomp crate only exposes the user notification API when
+// pkg-config finds libseccomp 2.5 or later. The same probe turns on
+// seccomp_notify, which --seccomp-notify, --seccomp-learn and --seccomp-audit
+// are built with; without it they are refused at run time.
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(seccomp_notify)");
+    println!("cargo:rerun-if-env-changed=LIBSECCOMP_LIB_PATH");
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+
+    if let Ok(path) = env::var("LIBSECCOMP_LIB_PATH") {
+        env::set_var("PKG_CONFIG_PATH", Path::new(&path).join("pkgconfig"));
+    }
+    if env::var("TARGET").ok() != env::var("HOST").ok() {
+        env::set_var("PKG_CONFIG_ALLOW_CROSS", "1");
+    }
+
+    match pkg_config::Config::new()
+        .atleast_version("2.5.0")
+        .cargo_metadata(false)
+        .probe("libseccomp")
+    {
+        Ok(_) => println!("cargo:rustc-cfg=seccomp_notify"),
+        Err(e) => println!(
+            "cargo:warning=libseccomp >= 2.5 not found ({}), building without \
+             seccomp user notification (--seccomp-notify, --seccomp-learn, --seccomp-audit)",
+            e.to_string()
+                .lines()
+                .map(str::trim)
+                .find(|l| !l.is_empty())
+                .unwrap_or_default()
+        ),
+    }
+}
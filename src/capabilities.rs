@@ -1,7 +1,10 @@
-use crate::errors::ErrCode;
+use crate::cli::Args;
+use crate::errors::{ErrCode, SetupError};
 
-use capctl::caps::Cap;
-use capctl::caps::FullCapState;
+use capctl::caps::{ambient, bounding};
+use capctl::caps::{Cap, CapSet, CapState};
+use capctl::prctl::{self, Secbits};
+use nix::errno::Errno;
 
 const CAPABILITIES_DROP: [Cap; 21] = [
                         // Drop because it...
@@ -18,7 +21,7 @@ const CAPABILITIES_DROP: [Cap; 21] = [
     Cap::MAC_OVERRIDE, // same as above
     Cap::MKNOD, // allows programs to (re)create device files, even existing hardware
     Cap::SETFCAP, // allows setting of capabilities on a file
-    Cap::SYSLOG, // allows privileged syslog operations and view kernel 
+    Cap::SYSLOG, // allows privileged syslog operations and view kernel
     Cap::SYS_ADMIN, // allows a ton of stuff
     Cap::SYS_BOOT, // allows rebooting and loading new kernels
     Cap::SYS_MODULE, // allows loading or unloading kernel modules
@@ -29,13 +32,210 @@ const CAPABILITIES_DROP: [Cap; 21] = [
     Cap::WAKE_ALARM // allows interference with suspend (like CAP_BLOCK_SUSPEND)
 ];
 
-pub fn setcapabilities() -> Result<(), ErrCode> {
-    log::debug!("Dropping unwanted capabilities...");
-    if let Ok(mut caps) = FullCapState::get_current() {
-        caps.bounding.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-        caps.inheritable.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-        Ok(())
+// Capability sets the container process should end up with
+#[derive(Debug, Clone, Copy)]
+pub struct CapabilitySets {
+    pub bounding: CapSet,
+    pub effective: CapSet,
+    pub permitted: CapSet,
+    pub inheritable: CapSet,
+    pub ambient: CapSet,
+}
+
+impl CapabilitySets {
+    // Default set adjusted with --cap-add / --cap-drop, explicitly dropped
+    // capabilities win over added ones and dropping ALL over adding ALL,
+    // named capabilities can be added back. Each set can then be overridden.
+    pub fn from_args(args: &Args) -> Result<CapabilitySets, ErrCode> {
+        let mut caps: CapSet = !CAPABILITIES_DROP.iter().copied().collect::<CapSet>();
+        let add = parse_cap_names(&args.cap_add, "cap-add")?;
+        let drop = parse_cap_names(&args.cap_drop, "cap-drop")?;
+        if add.is_none() {
+            caps = !CapSet::empty();
+        }
+        if drop.is_none() {
+            caps.clear();
+        }
+        caps |= add.unwrap_or_else(CapSet::empty);
+        caps -= drop.unwrap_or_else(CapSet::empty);
+
+        let set_or = |list: &Option<String>, name, default| match list {
+            Some(list) => parse_cap_list(list, name),
+            None => Ok(default),
+        };
+        // Ambient capabilities have to be inheritable too, so by default
+        // asking for an ambient capability is enough
+        let ambient = set_or(&args.cap_ambient, "cap-ambient", CapSet::empty())?;
+        let sets = CapabilitySets {
+            bounding: set_or(&args.cap_bounding, "cap-bounding", caps)?,
+            effective: set_or(&args.cap_effective, "cap-effective", caps)?,
+            permitted: set_or(&args.cap_permitted, "cap-permitted", caps)?,
+            inheritable: set_or(&args.cap_inheritable, "cap-inheritable", ambient)?,
+            ambient,
+        };
+
+        // Same invariants the kernel enforces on capset(2) and PR_CAP_AMBIENT
+        if !(sets.effective - sets.permitted).is_empty() {
+            log::error!("Effective capabilities must be a subset of the permitted ones");
+            return Err(ErrCode::InvalidArgument("cap-effective"));
+        }
+        if !(sets.ambient - (sets.permitted & sets.inheritable)).is_empty() {
+            log::error!("Ambient capabilities must be both permitted and inheritable");
+            return Err(ErrCode::InvalidArgument("cap-ambient"));
+        }
+        Ok(sets)
+    }
+}
+
+// Some(set) for a list of names, None if it contains ALL
+fn parse_cap_names(names: &[String], arg: &'static str) -> Result<Option<CapSet>, ErrCode> {
+    let mut set = CapSet::empty();
+    let mut all = false;
+    for name in names.iter().flat_map(|n| n.split(',')) {
+        if name.eq_ignore_ascii_case("ALL") {
+            all = true;
+        } else {
+            set.add(parse_cap(name, arg)?);
+        }
+    }
+    Ok(if all { None } else { Some(set) })
+}
+
+fn parse_cap_list(list: &str, arg: &'static str) -> Result<CapSet, ErrCode> {
+    let names: Vec<String> = list
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(parse_cap_names(&names, arg)?.unwrap_or_else(|| !CapSet::empty()))
+}
+
+// Accepts "CAP_NET_ADMIN" as well as "net_admin"
+fn parse_cap(name: &str, arg: &'static str) -> Result<Cap, ErrCode> {
+    let name = name.trim();
+    let full = if name.len() > 4 && name[..4].eq_ignore_ascii_case("CAP_") {
+        name.to_string()
     } else {
-        Err(ErrCode::CapabilitiesError(0))
+        format!("CAP_{}", name)
+    };
+    match full.parse::<Cap>() {
+        Ok(cap) => Ok(cap),
+        Err(_) => {
+            log::error!("Unknown capability {}", name);
+            Err(ErrCode::InvalidArgument(arg))
+        }
     }
-}
\ No newline at end of file
+}
+
+// Securebits that can be asked for on the command line, with or without
+// the SECBIT_ prefix
+const SECUREBITS: [(&str, Secbits); 4] = [
+    ("NOROOT", Secbits::NOROOT),
+    ("NOROOT_LOCKED", Secbits::NOROOT_LOCKED),
+    ("NO_SETUID_FIXUP", Secbits::NO_SETUID_FIXUP),
+    ("NO_SETUID_FIXUP_LOCKED", Secbits::NO_SETUID_FIXUP_LOCKED),
+];
+
+pub fn parse_securebits(names: &[String]) -> Result<Secbits, ErrCode> {
+    let mut bits = Secbits::empty();
+    for name in names.iter().flat_map(|n| n.split(',')) {
+        let name = name.trim().replace('-', "_");
+        let name = match name.get(..7) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("SECBIT_") => &name[7..],
+            _ => &name[..],
+        };
+        match SECUREBITS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, bit)) => bits |= *bit,
+            None => {
+                log::error!("Unknown securebit {}", name);
+                return Err(ErrCode::InvalidArgument("securebits"));
+            }
+        }
+    }
+    Ok(bits)
+}
+
+// Has to run while we still hold CAP_SETPCAP, before switching user
+pub fn restrict_bounding_set(caps: &CapabilitySets) -> Result<(), SetupError> {
+    log::debug!("Restricting capability bounding set...");
+    for cap in Cap::probe_supported() - caps.bounding {
+        if let Err(e) = bounding::ensure_dropped(cap) {
+            log::error!("Cannot drop {} from bounding set: {}", cap, e);
+            return Err(ErrCode::CapabilitiesError(0).caused_by(errno(e)));
+        }
+    }
+    Ok(())
+}
+
+pub fn setcapabilities(caps: &CapabilitySets) -> Result<(), SetupError> {
+    log::debug!("Setting capabilities...");
+    let current = match CapState::get_current() {
+        Ok(state) => state,
+        Err(e) => return Err(ErrCode::CapabilitiesError(1).caused_by(errno(e))),
+    };
+
+    // Only what survived the user switch can be handed out
+    let available = current.permitted | current.inheritable;
+    let lost = (caps.permitted | caps.inheritable) - available;
+    if !lost.is_empty() {
+        log::warn!("Capabilities not available after user switch: {:?}", lost);
+    }
+
+    let mut state = CapState::empty();
+    state.permitted = caps.permitted & current.permitted;
+    state.effective = caps.effective & state.permitted;
+    state.inheritable = caps.inheritable & available;
+    if let Err(e) = state.set_current() {
+        log::error!("Cannot set capabilities: {}", e);
+        return Err(ErrCode::CapabilitiesError(2).caused_by(errno(e)));
+    }
+
+    if let Err(e) = ambient::clear() {
+        log::error!("Cannot clear ambient capabilities: {}", e);
+        return Err(ErrCode::CapabilitiesError(3).caused_by(errno(e)));
+    }
+    // Ambient capabilities are what a non-root workload keeps across execve
+    for cap in caps.ambient & state.permitted & state.inheritable {
+        if let Err(e) = ambient::raise(cap) {
+            log::error!("Cannot raise ambient capability {}: {}", cap, e);
+            return Err(ErrCode::CapabilitiesError(3).caused_by(errno(e)));
+        }
+    }
+    Ok(())
+}
+
+// Needs CAP_SETPCAP, the locked bits can't be changed afterwards
+pub fn set_securebits(bits: Secbits) -> Result<(), SetupError> {
+    if bits.is_empty() {
+        return Ok(());
+    }
+    log::debug!("Setting securebits {:?}", bits);
+    let current = match prctl::get_securebits() {
+        Ok(current) => current,
+        Err(e) => return Err(ErrCode::CapabilitiesError(4).caused_by(errno(e))),
+    };
+    if let Err(e) = prctl::set_securebits(current | bits) {
+        log::error!("Cannot set securebits: {}", e);
+        return Err(ErrCode::CapabilitiesError(4).caused_by(errno(e)));
+    }
+    Ok(())
+}
+
+// Prevents execve from granting privileges through setuid binaries or file
+// capabilities, and lets the seccomp filter load without CAP_SYS_ADMIN
+pub fn set_no_new_privs(enabled: bool) -> Result<(), SetupError> {
+    if !enabled {
+        log::warn!("no_new_privs disabled, the container can gain privileges on execve");
+        return Ok(());
+    }
+    log::debug!("Setting no_new_privs");
+    if let Err(e) = prctl::set_no_new_privs() {
+        log::error!("Cannot set no_new_privs: {}", e);
+        return Err(ErrCode::CapabilitiesError(5).caused_by(errno(e)));
+    }
+    Ok(())
+}
+
+fn errno(e: capctl::Error) -> Errno {
+    Errno::from_i32(e.code())
+}
@@ -1,32 +1,61 @@
 use crate::config::ContainerOpts;
-use crate::errors::ErrCode;
+use crate::errors::{io_errno, ErrCode, SetupError};
 use crate::hostname::set_container_hostname;
 use crate::mounts::set_mountpoint;
-use crate::namespaces::userns;
-use crate::capabilities::setcapabilities;
+use crate::network::setup_network;
+use crate::namespaces::{switch_user, userns};
+use crate::capabilities::{
+    restrict_bounding_set, set_no_new_privs, set_securebits, setcapabilities,
+};
+use crate::environment::{container_env, lookup, DEFAULT_PATH};
+use crate::ipc::{send_message, wait_stage, Message, SetupStage, Stage};
+use crate::resources::set_rlimits;
 use crate::syscalls::setsyscalls;
+use crate::users::{resolve_user, User};
 
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
+use std::fs::metadata;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use nix::errno::Errno;
 use nix::sched::clone;
 use nix::sched::CloneFlags;
 use nix::sys::signal::Signal;
-use nix::unistd::{Pid, close, execve};
+use nix::sys::stat::umask;
+use nix::unistd::{access, chdir, execve, getpid, AccessFlags, Pid};
 
 const STACK_SIZE: usize = 1024 * 1024;
+const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
+// struct clone_args of clone3(2), up to the cgroup field (Linux 5.7)
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
 
 fn child(config: ContainerOpts) -> isize {
-    match setup_container_config(&config) {
-        Ok(_) => log::info!("Container configured successfully"),
-        Err(e) => {
-            log::error!("Error while configuring container: {:?}", e);
+    let user = match setup_container_config(&config) {
+        Ok(user) => user,
+        Err(report) => {
+            log::error!("Error while configuring container: {:?}", report);
+            // Our logs may not reach the terminal, the parent prints the error
+            send_message(config.fd, &report).ok();
             return -1;
         }
-    }
-
-    if let Err(_) = close(config.fd) {
-        log::error!("Error while closing socket...");
-        return -1;
-    }
+    };
+    log::info!("Container configured successfully");
 
     log::info!(
         "Starting container with command {} and args {:?}",
@@ -37,21 +66,72 @@ fn child(config: ContainerOpts) -> isize {
         config.argv
     );
 
-    let retcode = match execve::<CString, CString>(&config.path, &config.argv, &[]) {
-        Ok(_) => 0,
-        Err(e) => {
-            log::error!("Error while performing execve: {:?}", e);
-            -1
-        }
-    };
-
-    retcode
+    // The socket is closed on exec, it stays open to report a failure
+    let res = step(SetupStage::Exec, || {
+        let env = container_env(user.home.as_deref(), &config.hostname, &config.env);
+        log::debug!(
+            "Environment: {:?}",
+            env.iter().map(|v| &v.name).collect::<Vec<_>>()
+        );
+        let search = lookup(&env, "PATH").unwrap_or(DEFAULT_PATH);
+        let path = find_program(&config.path, search)?;
+        let env: Vec<CString> = env.iter().filter_map(|v| v.to_cstring()).collect();
+        execve::<CString, CString>(&path, &config.argv, &env)
+            .map(|_| ())
+            .map_err(|e| ErrCode::ChildProcessError(2).caused_by(e))
+    });
+    if let Err(report) = res {
+        log::error!("Error while performing execve: {:?}", report);
+        send_message(config.fd, &report).ok();
+    }
+    -1
+}
 
+// Looks the program up in the new root as execvp would, the error is caused
+// by the last place tried
+fn find_program(program: &CString, search: &str) -> Result<CString, SetupError> {
+    if program.as_bytes().contains(&b'/') {
+        return Ok(program.clone());
+    }
+    let mut errno = Errno::ENOENT;
+    for dir in search.split(':').filter(|d| !d.is_empty()) {
+        let path = Path::new(dir).join(OsStr::from_bytes(program.as_bytes()));
+        let found = match metadata(&path) {
+            Ok(m) if m.is_file() => access(&path, AccessFlags::X_OK),
+            Ok(_) => Err(Errno::EACCES),
+            Err(e) => Err(io_errno(&e)),
+        };
+        match found {
+            Ok(()) => {
+                log::debug!("Found {} in PATH", path.display());
+                return CString::new(path.into_os_string().into_vec())
+                    .map_err(|_| ErrCode::ChildProcessError(3).into());
+            }
+            Err(e) => errno = e,
+        }
+    }
+    Err(ErrCode::ChildProcessError(3).caused_by(errno))
 }
 
-pub fn generate_child_process(config: ContainerOpts) -> Result<Pid, ErrCode> {
-    let mut tmp_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// Runs a step of the setup, keeping the errno of the syscall it failed on
+fn step<T, E, F>(stage: SetupStage, f: F) -> Result<T, Message>
+where
+    E: Into<SetupError>,
+    F: FnOnce() -> Result<T, E>,
+{
+    f().map_err(|e| {
+        let e = e.into();
+        Message::SetupFailed {
+            stage,
+            error: e.code,
+            errno: e.errno.map(|errno| errno as i32),
+        }
+    })
+}
 
+// Returns whether the child already is in the cgroup, otherwise the caller
+// has to move it there before letting it continue
+pub fn generate_child_process(config: ContainerOpts, cgroup: Option<RawFd>) -> Result<(Pid, bool), ErrCode> {
     let mut flags = CloneFlags::empty();
     flags.insert(CloneFlags::CLONE_NEWNS); // start cloned child in new mount namespace
     flags.insert(CloneFlags::CLONE_NEWCGROUP); // used to restrict capabilities of child process
@@ -60,6 +140,18 @@ pub fn generate_child_process(config: ContainerOpts) -> Result<Pid, ErrCode> {
     flags.insert(CloneFlags::CLONE_NEWNET); // similarly, for network interfaces/configs
     flags.insert(CloneFlags::CLONE_NEWUTS); // isolation of hostname
 
+    if let Some(cgroup) = cgroup {
+        match clone_into_cgroup(&config, flags, cgroup) {
+            Ok(pid) => {
+                log::debug!("Child process PID: {} (cloned into its cgroup)", pid);
+                return Ok((pid, true));
+            }
+            // Kernels before 5.7 or without a cgroup v2 hierarchy
+            Err(e) => log::debug!("clone3 into cgroup failed ({}), falling back to clone", e),
+        }
+    }
+
+    let mut tmp_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
     match clone(
         Box::new(|| child(config.clone())),
         &mut tmp_stack,
@@ -68,17 +160,92 @@ pub fn generate_child_process(config: ContainerOpts) -> Result<Pid, ErrCode> {
     ) {
         Ok(pid) => {
             log::debug!("Child process PID: {}", pid);
-            Ok(pid)
+            Ok((pid, false))
         }
         Err(_) => Err(ErrCode::ChildProcessError(0)),
     }
 }
 
-fn setup_container_config(config: &ContainerOpts) -> Result<(), ErrCode> {
-    set_container_hostname(&config.hostname)?;
-    set_mountpoint(&config.mount_dir, &config.addpaths)?;
-    userns(config.fd, config.uid)?;
-    setcapabilities()?;
-    setsyscalls()?;
+// Like fork, the child runs on a copy of the parent's stack and never
+// returns from here
+fn clone_into_cgroup(config: &ContainerOpts, flags: CloneFlags, cgroup: RawFd) -> Result<Pid, Errno> {
+    let mut args = CloneArgs {
+        flags: flags.bits() as u64 | CLONE_INTO_CGROUP,
+        exit_signal: Signal::SIGCHLD as u64,
+        cgroup: cgroup as u64,
+        ..Default::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &mut args as *mut CloneArgs,
+            std::mem::size_of::<CloneArgs>(),
+        )
+    };
+    match ret {
+        -1 => Err(Errno::last()),
+        0 => {
+            let retcode = child(config.clone());
+            unsafe { libc::_exit(retcode as i32) }
+        }
+        pid => Ok(Pid::from_raw(pid as i32)),
+    }
+}
+
+fn set_workdir(workdir: &Path) -> Result<(), SetupError> {
+    if let Err(e) = chdir(workdir) {
+        log::error!("Cannot change directory to {}: {}", workdir.display(), e);
+        return Err(ErrCode::ChildProcessError(4).caused_by(e));
+    }
     Ok(())
 }
+
+// The error is the message reporting the failed step to the parent, the
+// user the container runs as is only known once in the new root
+fn setup_container_config(config: &ContainerOpts) -> Result<User, Message> {
+    let fd = config.fd;
+    step(SetupStage::Cgroup, || {
+        send_message(fd, &Message::Pid(getpid().as_raw()))?;
+        // Nothing runs before the parent has put the process in its cgroup
+        wait_stage(fd, Stage::Cgroup)
+    })?;
+    step(SetupStage::Hostname, || set_container_hostname(&config.hostname))?;
+    step(SetupStage::Network, || setup_network(fd, config.network))?;
+    step(SetupStage::Mounts, || {
+        set_mountpoint(&config.mount_dir, &config.addpaths, &config.devices)
+    })?;
+    step(SetupStage::UserNamespace, || userns(fd))?;
+    step(SetupStage::Rlimits, || set_rlimits(&config.limits.ulimits))?;
+    let security = &config.security;
+    step(SetupStage::BoundingSet, || {
+        restrict_bounding_set(&security.capabilities)
+    })?;
+    step(SetupStage::Securebits, || set_securebits(security.securebits))?;
+    step(SetupStage::NoNewPrivs, || {
+        set_no_new_privs(security.no_new_privs)
+    })?;
+    // Like runc, the filter goes in as late as possible, unless it needs
+    // CAP_SYS_ADMIN to load because no_new_privs is disabled
+    if !security.no_new_privs {
+        step(SetupStage::Seccomp, || setsyscalls(&security.seccomp, fd))?;
+    }
+    let user = step(SetupStage::SwitchUser, || -> Result<User, SetupError> {
+        let user = resolve_user(&config.user, config.userns_range)?;
+        switch_user(&user)?;
+        Ok(user)
+    })?;
+    step(SetupStage::Capabilities, || {
+        setcapabilities(&security.capabilities)
+    })?;
+    // As the user, who may not be allowed in there
+    if let Some(workdir) = &config.workdir {
+        step(SetupStage::Workdir, || set_workdir(workdir))?;
+    }
+    if let Some(mask) = config.umask {
+        umask(mask);
+    }
+    if security.no_new_privs {
+        step(SetupStage::Seccomp, || setsyscalls(&security.seccomp, fd))?;
+    }
+    Ok(user)
+}
@@ -1,22 +1,85 @@
+use std::ffi::OsString;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::devices::DeviceMapping;
+use crate::environment::EnvVar;
 use crate::errors::ErrCode;
+use crate::hostname::valid_hostname;
+use crate::network::{NetworkMode, PortForward};
+use crate::resources::{valid_cgroup_parent, ByteSize, DeviceRate, Ulimit};
+use crate::syscalls::{SeccompAudit, SeccompMode};
+use crate::namespaces::UsernsRange;
+use crate::users::UserSpec;
 
+// Parsed once at startup, the size of the run options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 #[structopt(name = "crabcan", about = "A simple container in Rust.")]
-pub struct Args {
+pub enum Command {
+    /// Run a command in a new container
+    Run(Args),
+    /// Show the resource usage of a running container
+    Stats(StatsArgs),
+    /// Stop every process of a running container, without killing them
+    Pause(ContainerArgs),
+    /// Restart the processes of a paused container
+    Resume(ContainerArgs),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ContainerArgs {
     /// Debug mode
     #[structopt(short, long)]
     pub debug: bool,
 
-    /// Command to execute within container
+    /// Id of the container, printed when it starts
+    pub id: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatsArgs {
+    /// Debug mode
     #[structopt(short, long)]
-    pub command: String,
+    pub debug: bool,
 
-    /// User ID to create inside container
+    /// Print the stats once as JSON instead of a live table
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Id of the container, printed when it starts
+    pub id: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    /// Debug mode
+    #[structopt(short, long)]
+    pub debug: bool,
+
+    /// Command to execute within container, split like a shell would
     #[structopt(short, long)]
-    pub uid: u32,
+    pub command: Option<String>,
+
+    /// User ID to run the command as, root by default
+    #[structopt(short, long, conflicts_with = "user")]
+    pub uid: Option<u32>,
+
+    /// User to run the command as: NAME|UID[:GROUP|GID], looked up in the /etc/passwd and /etc/group of the container, ids must be below the COUNT of --userns-range
+    #[structopt(long)]
+    pub user: Option<UserSpec>,
+
+    /// Host ids the ids of the container are mapped to, from 0: HOST_ID:COUNT
+    #[structopt(long = "userns-range", default_value = "10000:65536")]
+    pub userns_range: UsernsRange,
+
+    /// Working directory of the command within the container
+    #[structopt(parse(from_os_str), short, long)]
+    pub workdir: Option<PathBuf>,
+
+    /// File mode creation mask of the command, in octal
+    #[structopt(long, parse(try_from_str = parse_umask))]
+    pub umask: Option<u32>,
 
     /// Path to mount into the container
     #[structopt(parse(from_os_str), short = "m", long = "mount")]
@@ -28,16 +91,269 @@ pub struct Args {
 
     /// Additional paths to mount inside the container
     #[structopt(parse(from_os_str), short = "a", long = "add")]
-    pub addpaths: Vec<PathBuf>
+    pub addpaths: Vec<PathBuf>,
+
+    /// Environment variable of the container: KEY=VALUE, overrides the ones of --env-host
+    #[structopt(short, long)]
+    pub env: Vec<EnvVar>,
+
+    /// File of KEY=VALUE lines to add to the environment, overrides /etc/environment of the container
+    #[structopt(parse(from_os_str), long = "env-file")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Host environment variable to pass to the container, overrides the ones of --env-file
+    #[structopt(long = "env-host")]
+    pub env_host: Vec<String>,
+
+    /// Network of the container: "none" or "slirp" (user-mode networking)
+    #[structopt(long, default_value = "none")]
+    pub network: NetworkMode,
+
+    /// Publish a container port on the host (slirp only): HOST:CONTAINER[/tcp|/udp]
+    #[structopt(short = "p", long = "publish")]
+    pub publish: Vec<PortForward>,
+
+    /// Let the container reach the loopback of the host through 10.0.2.2 (slirp only)
+    #[structopt(long = "allow-host-loopback")]
+    pub allow_host_loopback: bool,
+
+    /// Cgroup under which the cgroup of the container is created
+    #[structopt(long = "cgroup-parent", default_value = "/crabcan")]
+    pub cgroup_parent: String,
+
+    /// Memory limit, 1g by default (bytes or with a k, m or g unit)
+    #[structopt(long)]
+    pub memory: Option<ByteSize>,
+
+    /// Memory and swap limit together, -1 for unlimited swap
+    #[structopt(long = "memory-swap", allow_hyphen_values = true)]
+    pub memory_swap: Option<ByteSize>,
+
+    /// Number of CPUs the container can use, can be fractional
+    #[structopt(long)]
+    pub cpus: Option<f64>,
+
+    /// Relative CPU weight against other containers, 256 by default
+    #[structopt(long = "cpu-shares")]
+    pub cpu_shares: Option<u64>,
+
+    /// CPUs the container can run on ("0-3", "0,1")
+    #[structopt(long = "cpuset-cpus")]
+    pub cpuset_cpus: Option<String>,
+
+    /// Memory nodes the container can allocate from ("0-3", "0,1")
+    #[structopt(long = "cpuset-mems")]
+    pub cpuset_mems: Option<String>,
+
+    /// Maximum number of processes, 64 by default, -1 for unlimited
+    #[structopt(long = "pids-limit", allow_hyphen_values = true)]
+    pub pids_limit: Option<i64>,
+
+    /// Relative block IO weight, from 10 to 1000, 50 by default
+    #[structopt(long = "blkio-weight")]
+    pub blkio_weight: Option<u16>,
+
+    /// Read rate limit of a block device in bytes per second: PATH:RATE
+    #[structopt(long = "device-read-bps")]
+    pub device_read_bps: Vec<DeviceRate>,
+
+    /// Write rate limit of a block device in bytes per second: PATH:RATE
+    #[structopt(long = "device-write-bps")]
+    pub device_write_bps: Vec<DeviceRate>,
+
+    /// Read rate limit of a block device in operations per second: PATH:RATE
+    #[structopt(long = "device-read-iops")]
+    pub device_read_iops: Vec<DeviceRate>,
+
+    /// Write rate limit of a block device in operations per second: PATH:RATE
+    #[structopt(long = "device-write-iops")]
+    pub device_write_iops: Vec<DeviceRate>,
+
+    /// Resource limit of the container process: NAME=SOFT[:HARD] (e.g. nofile=1024:2048)
+    #[structopt(long = "ulimit")]
+    pub ulimits: Vec<Ulimit>,
+
+    /// Host device to pass through: HOST[:CONTAINER][:PERMS], PERMS made of r, w and m
+    #[structopt(long = "device")]
+    pub devices: Vec<DeviceMapping>,
+
+    /// Write a JSON summary of the resources used by the container when it exits
+    #[structopt(parse(from_os_str), long = "stats-file")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Capabilities to add to the default set (name or ALL)
+    #[structopt(long = "cap-add")]
+    pub cap_add: Vec<String>,
+
+    /// Capabilities to drop from the default set (name or ALL), wins over --cap-add
+    #[structopt(long = "cap-drop")]
+    pub cap_drop: Vec<String>,
+
+    /// Override the bounding capability set (comma-separated names, ALL or "")
+    #[structopt(long = "cap-bounding")]
+    pub cap_bounding: Option<String>,
+
+    /// Override the effective capability set (comma-separated names, ALL or "")
+    #[structopt(long = "cap-effective")]
+    pub cap_effective: Option<String>,
+
+    /// Override the permitted capability set (comma-separated names, ALL or "")
+    #[structopt(long = "cap-permitted")]
+    pub cap_permitted: Option<String>,
+
+    /// Inheritable capability set, defaults to the ambient set (comma-separated names, ALL or "")
+    #[structopt(long = "cap-inheritable")]
+    pub cap_inheritable: Option<String>,
+
+    /// Capabilities kept across execve by a non-root user, empty by default (comma-separated names, ALL or "")
+    #[structopt(long = "cap-ambient")]
+    pub cap_ambient: Option<String>,
+
+    /// Don't set no_new_privs, setuid binaries and file capabilities can then raise privileges
+    #[structopt(long = "allow-new-privs")]
+    pub allow_new_privs: bool,
+
+    /// Securebits to set: NOROOT, NO_SETUID_FIXUP and their _LOCKED variants (comma-separated)
+    #[structopt(long)]
+    pub securebits: Vec<String>,
+
+    /// Seccomp profile in the Docker/OCI JSON format, replaces the built-in one
+    #[structopt(parse(from_os_str), long = "seccomp-profile")]
+    pub seccomp_profile: Option<PathBuf>,
+
+    /// Built-in seccomp profile: "denylist" (default) or "allowlist"
+    #[structopt(long = "seccomp-mode")]
+    pub seccomp_mode: Option<SeccompMode>,
+
+    /// Record the syscalls of the container and write an allowlist profile to this path
+    #[structopt(parse(from_os_str), long = "seccomp-learn")]
+    pub seccomp_learn: Option<PathBuf>,
+
+    /// Policy deciding on the syscalls it lists from a supervisor in the parent (JSON)
+    #[structopt(parse(from_os_str), long = "seccomp-notify")]
+    pub seccomp_notify: Option<PathBuf>,
+
+    /// Record the syscalls hit by deny rules and report them on exit: "report" or "complain" (let them through)
+    #[structopt(long = "seccomp-audit")]
+    pub seccomp_audit: Option<SeccompAudit>,
+
+    /// Program and arguments to execute within container, as given after --
+    #[structopt(last = true)]
+    pub argv: Vec<String>,
+}
+
+fn parse_umask(s: &str) -> Result<u32, ErrCode> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(ErrCode::InvalidArgument("umask")),
+    }
 }
 
-pub fn parse_args() -> Result<Args, ErrCode> {
-    let args = Args::from_args();
+pub fn parse_args() -> Result<Command, ErrCode> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    // Options without a subcommand are the ones of run, as before there
+    // were subcommands
+    let implicit_run = argv.get(1).and_then(|a| a.to_str()).is_some_and(|a| {
+        a.starts_with('-') && !["-h", "--help", "-V", "--version"].contains(&a)
+    });
+    if implicit_run {
+        argv.insert(1, OsString::from("run"));
+    }
+
+    match Command::from_iter(argv) {
+        Command::Run(args) => Ok(Command::Run(check_run_args(args)?)),
+        command => Ok(command),
+    }
+}
 
-    if args.command.is_empty() {
+// Splits a command line into arguments the way sh does, minus expansions:
+// quotes group words, a backslash escapes the next character (only ", \, $
+// and ` within double quotes)
+fn split_command(command: &str) -> Option<Vec<String>> {
+    let mut argv = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_ascii_whitespace() => argv.extend(arg.take()),
+            '\\' => arg.get_or_insert_with(String::new).push(chars.next()?),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => arg.push(c),
+                            c => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    argv.extend(arg);
+    Some(argv)
+}
+
+fn check_run_args(mut args: Args) -> Result<Args, ErrCode> {
+    // Either -c or the arguments after --, not both
+    match (&args.command, args.argv.is_empty()) {
+        (Some(command), true) => {
+            args.argv = split_command(command).ok_or(ErrCode::InvalidArgument("command"))?;
+        }
+        (None, false) => (),
+        _ => return Err(ErrCode::InvalidArgument("command")),
+    }
+    if args.argv.first().map_or(true, |program| program.is_empty()) {
         return Err(ErrCode::InvalidArgument("command"));
     }
 
+    if args.workdir.as_ref().is_some_and(|w| !w.is_absolute()) {
+        return Err(ErrCode::InvalidArgument("workdir"));
+    }
+
+    if args.hostname.as_ref().is_some_and(|h| !valid_hostname(h)) {
+        return Err(ErrCode::InvalidArgument("hostname"));
+    }
+
+    if !valid_cgroup_parent(&args.cgroup_parent) {
+        return Err(ErrCode::InvalidArgument("cgroup-parent"));
+    }
+
+    if !args.publish.is_empty() && args.network != NetworkMode::Slirp {
+        return Err(ErrCode::InvalidArgument("publish"));
+    }
+    if args.allow_host_loopback && args.network != NetworkMode::Slirp {
+        return Err(ErrCode::InvalidArgument("allow-host-loopback"));
+    }
+
+    // A profile replaces the built-in ones
+    if args.seccomp_profile.is_some() && args.seccomp_mode.is_some() {
+        return Err(ErrCode::InvalidArgument("seccomp-mode"));
+    }
+    if args.seccomp_learn.is_some()
+        && (args.seccomp_profile.is_some()
+            || args.seccomp_mode.is_some()
+            || args.seccomp_notify.is_some()
+            || args.seccomp_audit.is_some())
+    {
+        return Err(ErrCode::InvalidArgument("seccomp-learn"));
+    }
+
     Ok(args)
 }
 
@@ -1,45 +1,148 @@
+use crate::capabilities::{parse_securebits, CapabilitySets};
+use crate::cli::Args;
+use crate::devices::DeviceMapping;
+use crate::environment::EnvVar;
 use crate::errors::ErrCode;
 use crate::hostname::generate_hostname;
 use crate::ipc::generate_socket_pair;
+use crate::namespaces::UsernsRange;
+use crate::network::NetworkMode;
+#[cfg(seccomp_notify)]
+use crate::notify_policy::NotifyPolicy;
+use crate::resources::ResourceLimits;
+#[cfg(seccomp_notify)]
+use crate::seccomp_audit::SeccompAuditor;
+use crate::seccomp_profile::{SeccompFilter, SeccompProfile};
+use crate::syscalls::{builtin_filter, learn_filter, SeccompMode};
+use crate::users::UserSpec;
 
 use std::ffi::CString;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
+use capctl::prctl::Secbits;
+use nix::sys::stat::Mode;
+
+// Privilege restrictions applied to the container process
+#[derive(Clone)]
+pub struct SecurityOpts {
+    pub capabilities: CapabilitySets,
+    pub securebits: Secbits,
+    pub no_new_privs: bool,
+    pub seccomp: SeccompFilter,
+    #[cfg(seccomp_notify)]
+    pub notify_policy: Option<NotifyPolicy>,
+    #[cfg(seccomp_notify)]
+    pub auditor: Option<SeccompAuditor>,
+}
+
+impl SecurityOpts {
+    pub fn from_args(args: &Args) -> Result<SecurityOpts, ErrCode> {
+        let capabilities = CapabilitySets::from_args(args)?;
+        let mode = args.seccomp_mode.unwrap_or(SeccompMode::Denylist);
+        #[cfg_attr(not(seccomp_notify), allow(unused_mut))]
+        let mut seccomp = match &args.seccomp_profile {
+            Some(path) => SeccompProfile::load(path)?.to_filter(&capabilities)?,
+            None if args.seccomp_learn.is_some() => learn_filter(),
+            None => builtin_filter(mode, &capabilities),
+        };
+        #[cfg(not(seccomp_notify))]
+        if args.seccomp_notify.is_some()
+            || args.seccomp_learn.is_some()
+            || args.seccomp_audit.is_some()
+            || seccomp.uses_notify()
+        {
+            log::error!("Seccomp user notification needs crabcan built against libseccomp >= 2.5");
+            return Err(ErrCode::NotSupported(2));
+        }
+        #[cfg(seccomp_notify)]
+        let notify_policy = match &args.seccomp_notify {
+            Some(path) => Some(NotifyPolicy::load(path)?),
+            None => None,
+        };
+        #[cfg(seccomp_notify)]
+        if let Some(policy) = &notify_policy {
+            seccomp.notify(&policy.syscalls());
+        }
+        #[cfg(seccomp_notify)]
+        let auditor = args
+            .seccomp_audit
+            .map(|mode| SeccompAuditor::new(mode, &mut seccomp));
+        Ok(SecurityOpts {
+            capabilities,
+            securebits: parse_securebits(&args.securebits)?,
+            no_new_privs: !args.allow_new_privs,
+            seccomp,
+            #[cfg(seccomp_notify)]
+            notify_policy,
+            #[cfg(seccomp_notify)]
+            auditor,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct ContainerOpts {
     pub path: CString,
     pub argv: Vec<CString>,
-    pub uid: u32,
+    pub env: Vec<EnvVar>,
+    pub user: UserSpec,
+    pub userns_range: UsernsRange,
+    pub workdir: Option<PathBuf>,
+    pub umask: Option<Mode>,
     pub mount_dir: PathBuf,
     pub fd: RawFd,
     pub hostname: String,
     pub addpaths: Vec<(PathBuf, PathBuf)>,
+    pub devices: Vec<DeviceMapping>,
+    pub network: NetworkMode,
+    pub security: SecurityOpts,
+    pub limits: ResourceLimits,
 }
 
 impl ContainerOpts {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        command: &str,
-        uid: u32,
+        argv: &[String],
+        env: Vec<EnvVar>,
+        user: UserSpec,
+        userns_range: UsernsRange,
+        workdir: Option<PathBuf>,
+        umask: Option<Mode>,
         mount_dir: PathBuf,
         hostname: Option<String>,
         addpaths: Vec<(PathBuf, PathBuf)>,
+        devices: Vec<DeviceMapping>,
+        network: NetworkMode,
+        security: SecurityOpts,
+        limits: ResourceLimits,
     ) -> Result<(ContainerOpts, (RawFd, RawFd)), ErrCode> {
+        let argv: Vec<CString> = match argv.iter().map(|a| CString::new(a.as_str())).collect() {
+            Ok(argv) => argv,
+            Err(_) => return Err(ErrCode::InvalidArgument("command")),
+        };
+        let path = match argv.first() {
+            Some(path) => path.clone(),
+            None => return Err(ErrCode::InvalidArgument("command")),
+        };
         let sockets = generate_socket_pair()?;
-        let argv: Vec<CString> = command
-            .split_ascii_whitespace()
-            .map(|s| CString::new(s).expect("Cannot read arg"))
-            .collect();
-        let path = argv[0].clone();
         Ok((
             ContainerOpts {
                 path,
                 argv,
-                uid,
+                env,
+                user,
+                userns_range,
+                workdir,
+                umask,
                 mount_dir,
-                fd: sockets.1.clone(),
+                fd: sockets.1,
                 hostname: hostname.unwrap_or(generate_hostname()?),
                 addpaths,
+                devices,
+                network,
+                security,
+                limits,
             },
             sockets,
         ))
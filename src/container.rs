@@ -1,19 +1,50 @@
 use std::os::unix::io::RawFd;
+#[cfg(seccomp_notify)]
+use std::path::Path;
 use std::path::PathBuf;
+#[cfg(seccomp_notify)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use nix::sys::wait::waitpid;
+#[cfg(seccomp_notify)]
+use libseccomp::{ScmpNotifResp, ScmpNotifRespFlags};
+
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::{close, Pid};
 
 use crate::child::generate_child_process;
 use crate::cli::Args;
-use crate::config::ContainerOpts;
+use crate::config::{ContainerOpts, SecurityOpts};
+use crate::environment;
 use crate::errors::ErrCode;
+use crate::events::{CgroupEvents, EventWatcher};
+use crate::ipc::{
+    recv_fd, recv_message, recv_setup_error, send_error, send_stage, FdKind, Message, Stage,
+};
 use crate::mounts::clean_mounts;
 use crate::namespaces::handle_child_uid_map;
-use crate::resources::{clean_cgroups, restrict_resources};
+use crate::network::{NetworkMode, PortForward};
+use crate::resources::{
+    add_to_cgroup, cgroup_path, clean_cgroups, freeze_cgroup, open_cgroup, restrict_resources, CgroupMode,
+    ResourceLimits,
+};
+#[cfg(seccomp_notify)]
+use crate::seccomp_audit::SeccompAuditor;
+#[cfg(seccomp_notify)]
+use crate::seccomp_profile::SyscallLearner;
+use crate::slirp::Slirp;
+use crate::state::{generate_container_id, ContainerState, ContainerStatus};
+use crate::stats::{ContainerStats, ExitSummary};
+#[cfg(seccomp_notify)]
+use crate::supervisor::{syscall_name, Supervisor};
+use crate::users::UserSpec;
 
 pub const MINIMAL_KERNEL_VERSION: f32 = 4.8;
 
+// Architectures the seccomp filters are written for
+const SUPPORTED_MACHINES: [&str; 2] = ["x86_64", "aarch64"];
+
 pub fn check_linux_version() -> Result<(), ErrCode> {
     let host = nix::sys::utsname::uname();
     log::debug!("Linux release: {}", host.release());
@@ -26,7 +57,7 @@ pub fn check_linux_version() -> Result<(), ErrCode> {
         return Err(ErrCode::ContainerError(0));
     }
 
-    if host.machine() != "x86_64" {
+    if !SUPPORTED_MACHINES.contains(&host.machine()) {
         return Err(ErrCode::NotSupported(1));
     }
 
@@ -37,10 +68,31 @@ pub struct Container {
     sockets: (RawFd, RawFd),
     config: ContainerOpts,
     child_pid: Option<Pid>,
+    publish: Vec<PortForward>,
+    host_loopback: bool,
+    slirp: Option<Slirp>,
+    #[cfg(seccomp_notify)]
+    supervisor: Option<Supervisor>,
+    #[cfg(seccomp_notify)]
+    seccomp_learn: Option<PathBuf>,
+    #[cfg(seccomp_notify)]
+    learner: Arc<Mutex<SyscallLearner>>,
+    #[cfg(seccomp_notify)]
+    auditor: Option<Arc<Mutex<SeccompAuditor>>>,
+    events: Option<EventWatcher>,
+    started: Instant,
+    usage: ContainerStats,
+    stats_file: Option<PathBuf>,
+    state: ContainerState,
 }
 
 impl Container {
     pub fn new(args: Args) -> Result<Container, ErrCode> {
+        let security = SecurityOpts::from_args(&args)?;
+        let limits = ResourceLimits::from_args(&args)?;
+        #[cfg(seccomp_notify)]
+        let auditor = security.auditor.clone().map(|a| Arc::new(Mutex::new(a)));
+
         let mut addpaths = vec![];
         for ap_pair in args.addpaths.iter() {
             let mut pair = ap_pair.to_str().unwrap().split(":");
@@ -55,25 +107,120 @@ impl Container {
             addpaths.push((frompath, mountpath))
         }
 
+        let id = generate_container_id();
+        let cgroup = cgroup_path(&args.cgroup_parent, &id);
+
         let (config, sockets) = ContainerOpts::new(
-            &args.command,
-            args.uid,
+            &args.argv,
+            environment::from_args(&args)?,
+            args.user.unwrap_or_else(|| UserSpec::from_uid(args.uid.unwrap_or(0))),
+            args.userns_range,
+            args.workdir,
+            args.umask.map(Mode::from_bits_truncate),
             args.mount_dir,
             args.hostname,
             addpaths,
+            args.devices,
+            args.network,
+            security,
+            limits,
         )?;
+        let state = ContainerState::new(id, config.hostname.clone(), cgroup, CgroupMode::detect());
+        log::info!("Container id: {}", state.id);
         Ok(Container {
             sockets,
             config,
             child_pid: None,
+            publish: args.publish,
+            host_loopback: args.allow_host_loopback,
+            slirp: None,
+            #[cfg(seccomp_notify)]
+            supervisor: None,
+            #[cfg(seccomp_notify)]
+            seccomp_learn: args.seccomp_learn,
+            #[cfg(seccomp_notify)]
+            learner: Arc::new(Mutex::new(SyscallLearner::default())),
+            #[cfg(seccomp_notify)]
+            auditor,
+            events: None,
+            started: Instant::now(),
+            usage: ContainerStats::default(),
+            stats_file: args.stats_file,
+            state,
         })
     }
 
     pub fn create(&mut self) -> Result<(), ErrCode> {
-        let pid = generate_child_process(self.config.clone())?;
-        restrict_resources(&self.config.hostname, pid)?;
-        handle_child_uid_map(pid, self.sockets.0)?;
+        let mode = self.state.cgroup_mode;
+        let cgroup = restrict_resources(&self.state.cgroup_path, &self.config.limits, mode)?;
+        let cgroup_fd = open_cgroup(&self.state.cgroup_path, mode);
+        self.started = Instant::now();
+        let spawned = generate_child_process(self.config.clone(), cgroup_fd);
+        if let Some(fd) = cgroup_fd {
+            close(fd).ok();
+        }
+        // Only the child keeps its end, if it dies the socket gets closed
+        // instead of leaving us waiting for its messages
+        if let Err(e) = close(self.sockets.1) {
+            log::error!("Unable to close read socket: {:?}", e);
+            return Err(ErrCode::SocketError(3));
+        }
+        let (pid, in_cgroup) = spawned?;
+        match recv_message(self.sockets.0)? {
+            Message::Pid(child) => log::debug!("Child process is PID {} in its namespace", child),
+            msg => {
+                log::error!("Received {:?} from child, expected its PID", msg);
+                return Err(ErrCode::ChildProcessError(1));
+            }
+        }
+        if !in_cgroup {
+            if let Err(e) = add_to_cgroup(&cgroup, pid) {
+                send_error(self.sockets.0, &e, "Cannot add the child to its cgroup");
+                return Err(e);
+            }
+        }
+        // The limits are in place, the child can start its setup
+        send_stage(self.sockets.0, Stage::Cgroup)?;
+        if self.config.network == NetworkMode::Slirp {
+            let tap = recv_fd(self.sockets.0, FdKind::Tap)?;
+            self.slirp = Some(Slirp::spawn(tap, &self.publish, self.host_loopback)?);
+        }
+        handle_child_uid_map(pid, self.sockets.0, self.config.userns_range)?;
+        #[cfg(seccomp_notify)]
+        if self.config.security.seccomp.uses_notify() {
+            let listener = recv_fd(self.sockets.0, FdKind::SeccompNotify)?;
+            let supervisor = if self.seccomp_learn.is_some() {
+                let learner = self.learner.clone();
+                Supervisor::spawn(listener, move |_, req| {
+                    if let Ok(mut learner) = learner.lock() {
+                        learner.record(req);
+                    }
+                    ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::empty())
+                })?
+            } else {
+                // Syscalls of a profile notifying without a policy are denied
+                let policy = self.config.security.notify_policy.clone().unwrap_or_default();
+                let policy_syscalls = policy.syscalls();
+                let auditor = self.auditor.clone();
+                Supervisor::spawn(listener, move |fd, req| {
+                    // The policy has the last word on the syscalls it lists
+                    let name = syscall_name(req);
+                    if let Some(auditor) = &auditor {
+                        if let Ok(mut auditor) = auditor.lock() {
+                            if auditor.handles(&name) && !policy_syscalls.contains(&name) {
+                                return auditor.record(req);
+                            }
+                        }
+                    }
+                    policy.decide(fd, req)
+                })?
+            };
+            self.supervisor = Some(supervisor);
+        }
         self.child_pid = Some(pid);
+        self.state.pid = Some(pid.as_raw());
+        self.state.save()?;
+        self.events = Some(EventWatcher::spawn(&self.state)?);
         log::debug!("Creation finished");
         Ok(())
     }
@@ -81,36 +228,103 @@ impl Container {
     pub fn clean_exit(&mut self) -> Result<(), ErrCode> {
         log::debug!("Cleaning container");
 
-        if let Err(e) = close(self.sockets.0) {
-            log::error!("Unable to close write socket: {:?}", e);
-            return Err(ErrCode::SocketError(3));
+        if let Some(mut slirp) = self.slirp.take() {
+            slirp.stop();
         }
 
-        if let Err(e) = close(self.sockets.1) {
-            log::error!("Unable to close read socket: {:?}", e);
+        #[cfg(seccomp_notify)]
+        if let Some(mut supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+
+        #[cfg(seccomp_notify)]
+        if let Some(auditor) = &self.auditor {
+            if let Ok(auditor) = auditor.lock() {
+                auditor.report(&self.state.id);
+            }
+        }
+
+        if let Err(e) = close(self.sockets.0) {
+            log::error!("Unable to close write socket: {:?}", e);
             return Err(ErrCode::SocketError(3));
         }
 
         clean_mounts(&self.config.mount_dir)?;
 
-        if let Err(e) = clean_cgroups(&self.config.hostname) {
+        // Last look at the counters before the cgroup goes away
+        let watched = self.events.take().map(|mut e| e.stop()).unwrap_or_default();
+        let (cgroup, mode) = (&self.state.cgroup_path, self.state.cgroup_mode);
+        self.state.events = CgroupEvents::read(cgroup, mode);
+        self.state.events.pids_peak = self.state.events.pids_peak.max(watched.pids_peak);
+        self.usage = ContainerStats::read_cgroup(cgroup, mode).unwrap_or_default();
+
+        if let Err(e) = clean_cgroups(&self.state.cgroup_path, self.state.cgroup_mode) {
             log::error!("Cleaning cgroups failed: {}", e);
             return Err(e);
         }
 
+        self.state.remove()?;
+
+        #[cfg(seccomp_notify)]
+        if let Some(path) = &self.seccomp_learn {
+            self.save_learned_profile(path)?;
+        }
+
+        Ok(())
+    }
+
+    // How the container ended, an OOM kill is an error of its own
+    pub fn exit_summary(
+        &self,
+        status: Option<WaitStatus>,
+        wall_time: Duration,
+    ) -> Result<(), ErrCode> {
+        let summary = ExitSummary::new(
+            &self.state.id,
+            wall_time,
+            &self.usage,
+            &self.state.events,
+            status,
+        );
+        summary.log();
+        self.state.events.report(&self.state.id);
+        if let Some(path) = &self.stats_file {
+            summary.save(path)?;
+        }
+        if summary.oom_killed {
+            return Err(ErrCode::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    #[cfg(seccomp_notify)]
+    fn save_learned_profile(&self, path: &Path) -> Result<(), ErrCode> {
+        let learner = match self.learner.lock() {
+            Ok(learner) => learner,
+            Err(_) => return Err(ErrCode::SyscallsError(7)),
+        };
+        if learner.is_empty() {
+            log::warn!("No syscall recorded, not writing {}", path.display());
+            return Ok(());
+        }
+        learner.to_profile().save(path)?;
+        log::info!("Seccomp profile written to {}", path.display());
         Ok(())
     }
 }
 
-pub fn wait_child(pid: Option<Pid>) -> Result<(), ErrCode> {
+pub fn wait_child(pid: Option<Pid>) -> Result<Option<WaitStatus>, ErrCode> {
     if let Some(child_pid) = pid {
         log::debug!("Waiting for child (pid-{}) to finish", child_pid);
-        if let Err(e) = waitpid(child_pid, None) {
-            log::error!("Error while waiting for child to finish: {:?}", e);
-            return Err(ErrCode::ContainerError(1));
+        match waitpid(child_pid, None) {
+            Ok(status) => return Ok(Some(status)),
+            Err(e) => {
+                log::error!("Error while waiting for child to finish: {:?}", e);
+                return Err(ErrCode::ContainerError(1));
+            }
         }
     }
-    Ok(())
+    Ok(None)
 }
 
 pub fn start(args: Args) -> Result<(), ErrCode> {
@@ -122,12 +336,43 @@ pub fn start(args: Args) -> Result<(), ErrCode> {
         container.sockets.1
     );
     if let Err(e) = container.create() {
-        container.clean_exit().expect("Exit failure");
         log::error!("Error while creating container: {:?}", e);
+        // The creation error is the one to report, not a failed cleanup
+        if let Err(cleanup) = container.clean_exit() {
+            log::error!("Error while cleaning up after the failed creation: {:?}", cleanup);
+        }
         return Err(e);
     }
     log::debug!("Container child PID: {:?}", container.child_pid);
-    wait_child(container.child_pid)?;
+    let status = wait_child(container.child_pid)?;
+    let wall_time = container.started.elapsed();
+    // The setup may have failed after the creation, up to the execve
+    let setup_error = recv_setup_error(container.sockets.0);
     log::debug!("Finished, cleaning and exiting");
-    container.clean_exit()
+    container.clean_exit()?;
+    if let Some(e) = setup_error {
+        return Err(e);
+    }
+    container.exit_summary(status, wall_time)
+}
+
+// Freezes or thaws the processes of a running container, they keep their
+// memory and open files meanwhile
+pub fn set_paused(id: &str, paused: bool) -> Result<(), ErrCode> {
+    let (from, to) = if paused {
+        (ContainerStatus::Running, ContainerStatus::Paused)
+    } else {
+        (ContainerStatus::Paused, ContainerStatus::Running)
+    };
+    ContainerState::update(id, |state| {
+        if state.status != from {
+            log::error!("Container {} is {:?}, not {:?}", id, state.status, from);
+            return Err(ErrCode::ContainerError(3));
+        }
+        freeze_cgroup(&state.cgroup_path, state.cgroup_mode, paused)?;
+        state.set_status(to);
+        Ok(())
+    })?;
+    log::info!("Container {} {}", id, if paused { "paused" } else { "resumed" });
+    Ok(())
 }
@@ -0,0 +1,352 @@
+use std::fs::metadata;
+use std::mem::size_of;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use cgroups_rs::devices::{DevicePermissions, DeviceType};
+use nix::sys::stat::{major, minor};
+use nix::unistd::close;
+
+use crate::errors::ErrCode;
+
+// Bits of the access the kernel asks a device program about
+const ACCESS_MKNOD: u32 = 1;
+const ACCESS_READ: u32 = 2;
+const ACCESS_WRITE: u32 = 4;
+const ACCESS_ALL: u32 = ACCESS_MKNOD | ACCESS_READ | ACCESS_WRITE;
+
+// Character devices every container gets, like the other runtimes: null,
+// zero, full, random, urandom, tty, ptmx and the pseudo-terminals. tun is
+// opened by the child itself for the slirp network.
+const DEFAULT_DEVICES: [(u32, Option<u32>); 9] = [
+    (1, Some(3)),
+    (1, Some(5)),
+    (1, Some(7)),
+    (1, Some(8)),
+    (1, Some(9)),
+    (5, Some(0)),
+    (5, Some(2)),
+    (136, None),
+    (10, Some(200)),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    All,
+    Char,
+    Block,
+}
+
+// Access to the devices matching a type and numbers, None matches any number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRule {
+    pub allow: bool,
+    pub kind: DeviceKind,
+    pub major: Option<u32>,
+    pub minor: Option<u32>,
+    pub access: u32,
+}
+
+fn parse_access(s: &str) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    s.chars().try_fold(0, |access, c| match c {
+        'r' => Some(access | ACCESS_READ),
+        'w' => Some(access | ACCESS_WRITE),
+        'm' => Some(access | ACCESS_MKNOD),
+        _ => None,
+    })
+}
+
+impl DeviceRule {
+    pub fn cgroup_type(&self) -> DeviceType {
+        match self.kind {
+            DeviceKind::All => DeviceType::All,
+            DeviceKind::Char => DeviceType::Char,
+            DeviceKind::Block => DeviceType::Block,
+        }
+    }
+
+    // -1 is any for the devices controller of v1
+    pub fn cgroup_numbers(&self) -> (i64, i64) {
+        let number = |n: Option<u32>| n.map_or(-1, i64::from);
+        (number(self.major), number(self.minor))
+    }
+
+    pub fn cgroup_access(&self) -> Vec<DevicePermissions> {
+        let mut access = vec![];
+        for (bit, permission) in [
+            (ACCESS_READ, DevicePermissions::Read),
+            (ACCESS_WRITE, DevicePermissions::Write),
+            (ACCESS_MKNOD, DevicePermissions::MkNod),
+        ] {
+            if self.access & bit != 0 {
+                access.push(permission);
+            }
+        }
+        access
+    }
+}
+
+// Host device passed through to the container: HOST[:CONTAINER][:PERMS]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMapping {
+    pub host: PathBuf,
+    pub container: PathBuf,
+    pub access: u32,
+}
+
+impl FromStr for DeviceMapping {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        // HOST:PERMS is told apart from HOST:CONTAINER by the leading /
+        let (host, container, access) = match parts[..] {
+            [host] => (host, host, "rwm"),
+            [host, perms] if !perms.starts_with('/') => (host, host, perms),
+            [host, container] => (host, container, "rwm"),
+            [host, container, perms] => (host, container, perms),
+            _ => return Err(ErrCode::InvalidArgument("device")),
+        };
+        if !host.starts_with('/') || !container.starts_with('/') {
+            return Err(ErrCode::InvalidArgument("device"));
+        }
+        Ok(DeviceMapping {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            access: parse_access(access).ok_or(ErrCode::InvalidArgument("device"))?,
+        })
+    }
+}
+
+impl DeviceMapping {
+    fn rule(&self) -> Result<DeviceRule, ErrCode> {
+        let meta = match metadata(&self.host) {
+            Ok(m) => m,
+            Err(e) => {
+                log::error!("Cannot use device {}: {}", self.host.display(), e);
+                return Err(ErrCode::InvalidArgument("device"));
+            }
+        };
+        let kind = if meta.file_type().is_char_device() {
+            DeviceKind::Char
+        } else if meta.file_type().is_block_device() {
+            DeviceKind::Block
+        } else {
+            log::error!("{} is not a device", self.host.display());
+            return Err(ErrCode::InvalidArgument("device"));
+        };
+        Ok(DeviceRule {
+            allow: true,
+            kind,
+            major: Some(major(meta.rdev()) as u32),
+            minor: Some(minor(meta.rdev()) as u32),
+            access: self.access,
+        })
+    }
+}
+
+// Every device is denied but the default ones and those passed through.
+// Later rules take precedence over earlier ones.
+pub fn device_rules(mappings: &[DeviceMapping]) -> Result<Vec<DeviceRule>, ErrCode> {
+    let mut rules = vec![DeviceRule {
+        allow: false,
+        kind: DeviceKind::All,
+        major: None,
+        minor: None,
+        access: ACCESS_ALL,
+    }];
+    for (major, minor) in DEFAULT_DEVICES {
+        rules.push(DeviceRule {
+            allow: true,
+            kind: DeviceKind::Char,
+            major: Some(major),
+            minor,
+            access: ACCESS_ALL,
+        });
+    }
+    for mapping in mappings.iter() {
+        rules.push(mapping.rule()?);
+    }
+    Ok(rules)
+}
+
+// cgroup v2 has no devices controller, a BPF_PROG_TYPE_CGROUP_DEVICE program
+// attached to the cgroup decides instead
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 2;
+
+// Device types in the context of the program
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+
+// Instruction classes and operations
+const BPF_LDX_MEM_W: u8 = 0x61;
+const BPF_ALU64_AND_K: u8 = 0x57;
+const BPF_ALU64_RSH_K: u8 = 0x77;
+const BPF_ALU64_MOV_K: u8 = 0xb7;
+const BPF_ALU64_MOV_X: u8 = 0xbf;
+const BPF_JMP_JNE_K: u8 = 0x55;
+const BPF_JMP_JNE_X: u8 = 0x5d;
+const BPF_EXIT: u8 = 0x95;
+
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R5: u8 = 5;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+// The program gets a struct bpf_cgroup_dev_ctx { u32 access_type; u32 major;
+// u32 minor; } in r1, access_type being (access << 16) | type. It returns 1
+// to allow the access, 0 to deny it.
+fn device_program(rules: &[DeviceRule]) -> Vec<BpfInsn> {
+    let mut program = vec![
+        insn(BPF_LDX_MEM_W, R2, R1, 0, 0),
+        insn(BPF_ALU64_AND_K, R2, 0, 0, 0xffff),
+        insn(BPF_LDX_MEM_W, R3, R1, 0, 0),
+        insn(BPF_ALU64_RSH_K, R3, 0, 0, 16),
+        insn(BPF_LDX_MEM_W, R4, R1, 4, 0),
+        insn(BPF_LDX_MEM_W, R5, R1, 8, 0),
+    ];
+
+    // The first matching rule decides, so the last ones are checked first
+    for rule in rules.iter().rev() {
+        // Each test jumps to the next rule when the device doesn't match
+        let mut block = vec![];
+        match rule.kind {
+            DeviceKind::Block => block.push(insn(BPF_JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_BLOCK)),
+            DeviceKind::Char => block.push(insn(BPF_JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_CHAR)),
+            DeviceKind::All => (),
+        }
+        if rule.access != ACCESS_ALL {
+            // Every requested access has to be in the rule
+            block.push(insn(BPF_ALU64_MOV_X, R1, R3, 0, 0));
+            block.push(insn(BPF_ALU64_AND_K, R1, 0, 0, rule.access as i32));
+            block.push(insn(BPF_JMP_JNE_X, R1, R3, 0, 0));
+        }
+        if let Some(major) = rule.major {
+            block.push(insn(BPF_JMP_JNE_K, R4, 0, 0, major as i32));
+        }
+        if let Some(minor) = rule.minor {
+            block.push(insn(BPF_JMP_JNE_K, R5, 0, 0, minor as i32));
+        }
+        block.push(insn(BPF_ALU64_MOV_K, R0, 0, 0, rule.allow as i32));
+        block.push(insn(BPF_EXIT, 0, 0, 0, 0));
+
+        let len = block.len();
+        let mut matches_all = true;
+        for (i, ins) in block.iter_mut().enumerate() {
+            if ins.code == BPF_JMP_JNE_K || ins.code == BPF_JMP_JNE_X {
+                ins.off = (len - i - 1) as i16;
+                matches_all = false;
+            }
+        }
+        program.extend(block);
+        // The verifier rejects the unreachable rules after it
+        if matches_all {
+            return program;
+        }
+    }
+
+    program.push(insn(BPF_ALU64_MOV_K, R0, 0, 0, 0));
+    program.push(insn(BPF_EXIT, 0, 0, 0, 0));
+    program
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+    replace_bpf_fd: u32,
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> libc::c_long {
+    unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, size_of::<T>()) }
+}
+
+// Loads the program of the rules and attaches it to the cgroup, the cgroup
+// keeps it alive from then on
+pub fn attach_device_filter(cgroup: RawFd, rules: &[DeviceRule]) -> Result<(), ErrCode> {
+    let program = device_program(rules);
+    let license = c"GPL";
+    let mut name = [0u8; 16];
+    name[..7].copy_from_slice(b"crabcan");
+    let load = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        prog_name: name,
+        ..Default::default()
+    };
+    let prog = bpf(BPF_PROG_LOAD, &load);
+    if prog < 0 {
+        log::error!(
+            "Cannot load the device filter: {}",
+            std::io::Error::last_os_error()
+        );
+        return Err(ErrCode::ResourcesError(7));
+    }
+
+    let attach = ProgAttachAttr {
+        target_fd: cgroup as u32,
+        attach_bpf_fd: prog as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+        ..Default::default()
+    };
+    let res = bpf(BPF_PROG_ATTACH, &attach);
+    let err = std::io::Error::last_os_error();
+    close(prog as RawFd).ok();
+    if res < 0 {
+        log::error!("Cannot attach the device filter: {}", err);
+        return Err(ErrCode::ResourcesError(7));
+    }
+    Ok(())
+}
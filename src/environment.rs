@@ -0,0 +1,160 @@
+use std::ffi::CString;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cli::Args;
+use crate::errors::ErrCode;
+
+// The environment of the container process is merged from these sources,
+// each one overriding the variables of the ones before:
+//   1. defaults: PATH, HOME of the user in the /etc/passwd of the container
+//      and HOSTNAME
+//   2. /etc/environment of the container, the variables of the image
+//   3. --env-file files, in the order they are given
+//   4. --env-host variables of the host
+//   5. --env
+// The first two are only known within the new root, the child merges them
+// with the others read by the parent.
+
+// Where a program given without a / is looked for, like the default PATH
+// of other runtimes
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: String,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('=') && !name.contains('\0')
+}
+
+impl FromStr for EnvVar {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) if valid_name(name) && !value.contains('\0') => Ok(EnvVar {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(ErrCode::InvalidArgument("env")),
+        }
+    }
+}
+
+impl EnvVar {
+    fn new(name: &str, value: &str) -> EnvVar {
+        EnvVar {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    pub fn to_cstring(&self) -> Option<CString> {
+        CString::new(format!("{}={}", self.name, self.value)).ok()
+    }
+}
+
+// Replaces the variable of the same name, keeping its place
+fn set(env: &mut Vec<EnvVar>, var: EnvVar) {
+    match env.iter_mut().find(|v| v.name == var.name) {
+        Some(v) => v.value = var.value,
+        None => env.push(var),
+    }
+}
+
+pub fn lookup<'a>(env: &'a [EnvVar], name: &str) -> Option<&'a str> {
+    env.iter()
+        .find(|v| v.name == name)
+        .map(|v| v.value.as_str())
+}
+
+// KEY=VALUE lines, blank lines and # comments are skipped
+fn parse_lines(content: &str) -> Vec<(usize, Result<EnvVar, ErrCode>)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .map(|(i, l)| (i, l.parse()))
+        .collect()
+}
+
+fn read_env_file(path: &Path) -> Result<Vec<EnvVar>, ErrCode> {
+    let content = match read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Cannot read env file {}: {}", path.display(), e);
+            return Err(ErrCode::InvalidArgument("env-file"));
+        }
+    };
+    let mut env = vec![];
+    for (line, var) in parse_lines(&content) {
+        match var {
+            Ok(var) => env.push(var),
+            Err(_) => {
+                log::error!("{}:{}: expected KEY=VALUE", path.display(), line);
+                return Err(ErrCode::InvalidArgument("env-file"));
+            }
+        }
+    }
+    Ok(env)
+}
+
+// Variables given to crabcan, merged in their order of precedence
+pub fn from_args(args: &Args) -> Result<Vec<EnvVar>, ErrCode> {
+    let mut env = vec![];
+    for path in args.env_files.iter() {
+        for var in read_env_file(path)? {
+            set(&mut env, var);
+        }
+    }
+    for name in args.env_host.iter() {
+        if !valid_name(name) {
+            return Err(ErrCode::InvalidArgument("env-host"));
+        }
+        // Like docker, a variable the host doesn't have is left out
+        match std::env::var(name) {
+            Ok(value) => set(&mut env, EnvVar::new(name, &value)),
+            Err(e) => log::debug!("Host variable {} not passed: {}", name, e),
+        }
+    }
+    for var in args.env.iter() {
+        set(&mut env, var.clone());
+    }
+    Ok(env)
+}
+
+// Runs in the child once in the new root, adding the variables of the
+// rootfs to the ones given to crabcan
+pub fn container_env(home: Option<&str>, hostname: &str, given: &[EnvVar]) -> Vec<EnvVar> {
+    let mut env = vec![
+        EnvVar::new("PATH", DEFAULT_PATH),
+        EnvVar::new("HOME", home.unwrap_or("/")),
+        EnvVar::new("HOSTNAME", hostname),
+    ];
+    // pam_env syntax, values may be quoted
+    if let Ok(content) = read_to_string("/etc/environment") {
+        for (line, var) in parse_lines(&content) {
+            match var {
+                Ok(mut var) => {
+                    let quoted = var.value.len() >= 2
+                        && (var.value.starts_with('"') && var.value.ends_with('"')
+                            || var.value.starts_with('\'') && var.value.ends_with('\''));
+                    if quoted {
+                        var.value = var.value[1..var.value.len() - 1].to_string();
+                    }
+                    set(&mut env, var);
+                }
+                Err(_) => log::warn!("Skipping line {} of /etc/environment", line),
+            }
+        }
+    }
+    for var in given {
+        set(&mut env, var.clone());
+    }
+    env
+}
@@ -1,9 +1,16 @@
+use nix::errno::Errno;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
+use std::io;
 use std::process::exit;
 
-#[derive(Debug)]
+// Behind an alias serde doesn't take it for a string borrowed from the input
+type Element = &'static str;
+
+// Sent by the child to the parent when its setup fails
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ErrCode {
-    InvalidArgument(&'static str),
+    InvalidArgument(#[serde(deserialize_with = "leak_str")] Element),
     NotSupported(u8),
     ContainerError(u8),
     SocketError(u8),
@@ -14,19 +21,67 @@ pub enum ErrCode {
     CapabilitiesError(u8),
     SyscallsError(u8),
     ResourcesError(u8),
+    NetworkError(u8),
     RngError,
+    OutOfMemory,
+}
+
+// Exit code of crabcan when the OOM killer ended the container
+pub const OOM_RETCODE: i32 = 3;
+
+// Only the parent deserializes errors, right before exiting with them
+fn leak_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Element, D::Error> {
+    Ok(Box::leak(String::deserialize(deserializer)?.into_boxed_str()))
 }
 
 impl ErrCode {
     pub fn get_retcode(&self) -> i32 {
-        1
+        match self {
+            Self::OutOfMemory => OOM_RETCODE,
+            _ => 1,
+        }
+    }
+
+    // Keeps the errno of the failed syscall the error comes from, for the
+    // child to tell the parent with the error
+    pub fn caused_by(self, errno: Errno) -> SetupError {
+        SetupError {
+            code: self,
+            errno: Some(errno),
+        }
+    }
+}
+
+// Error of the container setup, with the errno taken where the syscall
+// failed as anything run after it, like logging, may overwrite errno
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetupError {
+    pub code: ErrCode,
+    pub errno: Option<Errno>,
+}
+
+impl From<ErrCode> for SetupError {
+    fn from(code: ErrCode) -> SetupError {
+        SetupError { code, errno: None }
     }
 }
 
+// Outside of the setup only the error code matters
+impl From<SetupError> for ErrCode {
+    fn from(e: SetupError) -> ErrCode {
+        e.code
+    }
+}
+
+pub fn io_errno(e: &io::Error) -> Errno {
+    Errno::from_i32(e.raw_os_error().unwrap_or(0))
+}
+
 impl fmt::Display for ErrCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
             Self::InvalidArgument(element) => write!(f, "InvalidArgument: {}", element),
+            Self::OutOfMemory => write!(f, "Container killed by the OOM killer"),
             _ => write!(f, "{:?}", self),
         }
     }
@@ -0,0 +1,248 @@
+use std::fs::read_to_string;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::unistd::close;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ErrCode;
+use crate::resources::{controller_dir, CgroupMode};
+use crate::state::ContainerState;
+
+const POLL_TIMEOUT_MS: i32 = 100;
+
+// Counters of the events files of the cgroup of a container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CgroupEvents {
+    // Times the memory usage went over the limit and got reclaimed
+    pub memory_max: u64,
+    // Times the OOM killer was invoked, and processes it killed
+    pub oom: u64,
+    pub oom_kill: u64,
+    // Forks that failed because of the pids limit
+    pub pids_max: u64,
+    // Most processes the container had at once
+    pub pids_peak: u64,
+}
+
+// Value of KEY in a file made of "KEY VALUE" lines, 0 if it isn't there
+fn read_counter(path: &Path, key: &str) -> u64 {
+    read_to_string(path)
+        .ok()
+        .and_then(|content| {
+            content.lines().find_map(|l| {
+                let (k, v) = l.split_once(' ')?;
+                if k == key {
+                    v.trim().parse().ok()
+                } else {
+                    None
+                }
+            })
+        })
+        .unwrap_or(0)
+}
+
+fn read_value(path: &Path) -> u64 {
+    read_to_string(path)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// Kernels before 6.1 have no pids.peak, the watcher then keeps the highest
+// pids.current it saw
+fn read_pids_peak(pids: &Path) -> u64 {
+    let peak = pids.join("pids.peak");
+    if peak.exists() {
+        read_value(&peak)
+    } else {
+        read_value(&pids.join("pids.current"))
+    }
+}
+
+impl CgroupEvents {
+    pub fn read(cgroup: &str, mode: CgroupMode) -> CgroupEvents {
+        let memory = controller_dir(cgroup, mode, "memory");
+        let pids = controller_dir(cgroup, mode, "pids");
+        let pids_events = pids.join("pids.events");
+        let pids_peak = read_pids_peak(&pids);
+        match mode {
+            CgroupMode::Unified => {
+                let events = memory.join("memory.events");
+                CgroupEvents {
+                    memory_max: read_counter(&events, "max"),
+                    oom: read_counter(&events, "oom"),
+                    oom_kill: read_counter(&events, "oom_kill"),
+                    pids_max: read_counter(&pids_events, "max"),
+                    pids_peak,
+                }
+            }
+            // v1 has no memory.events, and doesn't count the OOM events
+            // apart from the kills
+            _ => {
+                let oom_kill = read_counter(&memory.join("memory.oom_control"), "oom_kill");
+                CgroupEvents {
+                    memory_max: read_value(&memory.join("memory.failcnt")),
+                    oom: oom_kill,
+                    oom_kill,
+                    pids_max: read_counter(&pids_events, "max"),
+                    pids_peak,
+                }
+            }
+        }
+    }
+
+    fn log_changes(&self, previous: &CgroupEvents, container: &str) {
+        if self.oom_kill > previous.oom_kill {
+            log::warn!(
+                "Container {} ran out of memory, {} process(es) killed",
+                container,
+                self.oom_kill - previous.oom_kill
+            );
+        }
+        if self.pids_max > previous.pids_max {
+            log::warn!(
+                "Container {} hit its pids limit, {} fork(s) failed",
+                container,
+                self.pids_max - previous.pids_max
+            );
+        }
+        if self.memory_max > previous.memory_max {
+            log::debug!("Container {} reached its memory limit", container);
+        }
+    }
+
+    // What happened to the container during its life, for the exit summary
+    pub fn report(&self, container: &str) {
+        if self.oom_kill > 0 {
+            log::warn!(
+                "Container {}: the OOM killer killed {} process(es)",
+                container,
+                self.oom_kill
+            );
+        }
+        if self.pids_max > 0 {
+            log::warn!(
+                "Container {}: {} fork(s) failed on the pids limit",
+                container,
+                self.pids_max
+            );
+        }
+    }
+}
+
+// Follows the events files of the cgroup while the container runs, logging
+// OOM kills and pids limit hits as they happen and keeping the counters of
+// the state file up to date
+pub struct EventWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<CgroupEvents>>,
+}
+
+impl EventWatcher {
+    pub fn spawn(state: &ContainerState) -> Result<EventWatcher, ErrCode> {
+        let inotify = match Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC) {
+            Ok(i) => i,
+            Err(e) => {
+                log::error!("Cannot watch the cgroup events: {}", e);
+                return Err(ErrCode::ResourcesError(5));
+            }
+        };
+        // Only the events files of v2 notify on changes, the counters are
+        // polled too for v1 and the pids peak
+        let mut files = vec![];
+        if state.cgroup_mode == CgroupMode::Unified {
+            for (controller, file) in [("pids", "pids.events"), ("memory", "memory.events")] {
+                files.push(
+                    controller_dir(&state.cgroup_path, state.cgroup_mode, controller).join(file),
+                );
+            }
+        }
+        for file in files.iter().filter(|f| f.exists()) {
+            if let Err(e) = inotify.add_watch(file, AddWatchFlags::IN_MODIFY) {
+                log::warn!("Cannot watch {}: {}", file.display(), e);
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let (id, cgroup, mode) = (
+            state.id.clone(),
+            state.cgroup_path.clone(),
+            state.cgroup_mode,
+        );
+        match thread::Builder::new()
+            .name("cgroup-events".to_string())
+            .spawn(move || watch(inotify, &id, &cgroup, mode, &flag))
+        {
+            Ok(handle) => Ok(EventWatcher {
+                stop,
+                thread: Some(handle),
+            }),
+            Err(e) => {
+                log::error!("Cannot start cgroup events thread: {}", e);
+                close(inotify.as_raw_fd()).ok();
+                Err(ErrCode::ResourcesError(11))
+            }
+        }
+    }
+
+    // The counters as last seen by the watcher
+    pub fn stop(&mut self) -> CgroupEvents {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(|handle| handle.join()) {
+            Some(Ok(events)) => events,
+            Some(Err(_)) => {
+                log::error!("Cgroup events thread panicked");
+                CgroupEvents::default()
+            }
+            None => CgroupEvents::default(),
+        }
+    }
+}
+
+fn watch(
+    inotify: Inotify,
+    id: &str,
+    cgroup: &str,
+    mode: CgroupMode,
+    stop: &AtomicBool,
+) -> CgroupEvents {
+    let mut events = CgroupEvents::default();
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_TIMEOUT_MS) {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Cannot poll the cgroup events: {}", e);
+                break;
+            }
+        }
+        // Only the counters matter, not which file changed
+        inotify.read_events().ok();
+
+        let mut current = CgroupEvents::read(cgroup, mode);
+        current.pids_peak = current.pids_peak.max(events.pids_peak);
+        if current == events {
+            continue;
+        }
+        current.log_changes(&events, id);
+        events = current;
+        // Only the counters are ours, pause and resume change the status
+        let update = ContainerState::update(id, |state| {
+            state.events = events;
+            Ok(())
+        });
+        if update.is_err() {
+            log::debug!("State of container {} gone, not updating it", id);
+        }
+    }
+    close(inotify.as_raw_fd()).ok();
+    events
+}
@@ -1,7 +1,7 @@
 use nix::unistd::sethostname;
 use rand::seq::SliceRandom;
 
-use crate::errors::ErrCode;
+use crate::errors::{ErrCode, SetupError};
 
 const HOSTNAME_ADJ: [&'static str; 12] = [
     "tiny", "small", "normal", "medium", "large", "huge", "silent", "noisy", "rusty", "spotted",
@@ -38,15 +38,27 @@ pub fn generate_hostname() -> Result<String, ErrCode> {
     ))
 }
 
-pub fn set_container_hostname(hostname: &str) -> Result<(), ErrCode> {
+// RFC 1123: dot-separated labels of letters, digits and hyphens
+pub fn valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 64
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+pub fn set_container_hostname(hostname: &str) -> Result<(), SetupError> {
     match sethostname(hostname) {
         Ok(_) => {
             log::debug!("Container hostname set to {}", hostname);
             Ok(())
         }
-        Err(_) => {
-            log::error!("Cannot set hostname {} for container", hostname);
-            Err(ErrCode::HostnameError(0))
+        Err(e) => {
+            log::error!("Cannot set hostname {} for container: {}", hostname, e);
+            Err(ErrCode::HostnameError(0).caused_by(e))
         }
     }
 }
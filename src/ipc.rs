@@ -1,7 +1,80 @@
-use nix::sys::socket::{recv, send, socketpair, AddressFamily, MsgFlags, SockFlag, SockType};
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags,
+    SockFlag, SockType,
+};
+use nix::sys::uio::IoVec;
+use nix::unistd::close;
+use serde::{Deserialize, Serialize};
 use std::os::unix::io::RawFd;
 
-use crate::errors::ErrCode;
+use crate::errors::{ErrCode, SetupError};
+
+// First byte of every message, to be bumped on any change of the messages
+pub const PROTOCOL_VERSION: u8 = 3;
+
+// A SEQPACKET message is received whole or truncated, never split
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+// Points of the setup where the child waits for the parent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stage {
+    // The child is in its cgroup, with the limits in place
+    Cgroup,
+    // The uid / gid maps of the child are written
+    UidMap,
+}
+
+// Steps of the setup of the child, to tell the parent where it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetupStage {
+    Cgroup,
+    Hostname,
+    Network,
+    Mounts,
+    UserNamespace,
+    Rlimits,
+    BoundingSet,
+    Securebits,
+    NoNewPrivs,
+    Seccomp,
+    SwitchUser,
+    Capabilities,
+    Workdir,
+    Exec,
+}
+
+// Descriptors the child hands over to the parent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FdKind {
+    Tap,
+    SeccompNotify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    // The sender is done with this stage, the other end can go on
+    Stage(Stage),
+    // Whether the child could create its user namespace
+    UserNamespace(bool),
+    // The sender gave up, the other end should stop too
+    Error {
+        code: String,
+        message: String,
+    },
+    // Comes with the descriptor as SCM_RIGHTS ancillary data
+    Fd(FdKind),
+    // PID of the child within its own PID namespace
+    Pid(i32),
+    // The child could not set itself up and exits, errno is the one of the
+    // syscall the step failed on, if any
+    SetupFailed {
+        stage: SetupStage,
+        error: ErrCode,
+        errno: Option<i32>,
+    },
+}
 
 pub fn generate_socket_pair() -> Result<(RawFd, RawFd), ErrCode> {
     match socketpair(
@@ -15,20 +88,263 @@ pub fn generate_socket_pair() -> Result<(RawFd, RawFd), ErrCode> {
     }
 }
 
-pub fn send_boolean(fd: RawFd, msg: bool) -> Result<(), ErrCode> {
-    let data: [u8; 1] = [msg.into()];
-    if let Err(e) = send(fd, &data, MsgFlags::empty()) {
-        log::error!("Cannot send boolean through socket: {:?}", e);
-        return Err(ErrCode::SocketError(1));
+fn send_with_fd(fd: RawFd, msg: &Message, passed: Option<RawFd>) -> Result<(), SetupError> {
+    let mut data = vec![PROTOCOL_VERSION];
+    if let Err(e) = serde_json::to_writer(&mut data, msg) {
+        log::error!("Cannot serialize message {:?}: {}", msg, e);
+        return Err(ErrCode::SocketError(1).into());
+    }
+    let iov = [IoVec::from_slice(&data)];
+    let fds: Vec<RawFd> = passed.into_iter().collect();
+    let cmsg: Vec<ControlMessage> = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(&fds)]
     };
+    if let Err(e) = sendmsg(fd, &iov, &cmsg, MsgFlags::empty(), None) {
+        log::error!("Cannot send message {:?} through socket: {:?}", msg, e);
+        return Err(ErrCode::SocketError(1).caused_by(e));
+    }
     Ok(())
 }
 
-pub fn recv_boolean(fd: RawFd) -> Result<bool, ErrCode> {
-    let mut data: [u8; 1] = [0];
-    if let Err(e) = recv(fd, &mut data, MsgFlags::empty()) {
-        log::error!("Cannot receive boolean from socket: {:?}", e);
-        return Err(ErrCode::SocketError(2));
+// Nothing received if the other end closed the socket, or if the flags ask
+// not to wait and no message is queued
+fn recv_with_fd(
+    fd: RawFd,
+    flags: MsgFlags,
+) -> Result<Option<(Message, Option<RawFd>)>, SetupError> {
+    let mut data = [0u8; MAX_MESSAGE_SIZE];
+    let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+    let (size, truncated, passed) = {
+        let iov = [IoVec::from_mut_slice(&mut data)];
+        let flags = flags | MsgFlags::MSG_CMSG_CLOEXEC;
+        let msg = match recvmsg(fd, &iov, Some(&mut cmsg_buffer), flags) {
+            Ok(msg) => msg,
+            Err(Errno::EAGAIN) => return Ok(None),
+            Err(e) => {
+                log::error!("Cannot receive message from socket: {:?}", e);
+                return Err(ErrCode::SocketError(2).caused_by(e));
+            }
+        };
+        let passed = msg.cmsgs().find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None,
+        });
+        (msg.bytes, msg.flags.contains(MsgFlags::MSG_TRUNC), passed)
+    };
+
+    let decoded = if size == 0 {
+        return Ok(None);
+    } else if truncated {
+        log::error!(
+            "Message received from socket is over {} bytes",
+            MAX_MESSAGE_SIZE
+        );
+        Err(ErrCode::SocketError(2))
+    } else if data[0] != PROTOCOL_VERSION {
+        log::error!(
+            "Message of protocol version {} received, expected {}",
+            data[0],
+            PROTOCOL_VERSION
+        );
+        Err(ErrCode::SocketError(6))
+    } else {
+        serde_json::from_slice(&data[1..size]).map_err(|e| {
+            log::error!("Cannot decode message received from socket: {}", e);
+            ErrCode::SocketError(6)
+        })
+    };
+    match decoded {
+        Ok(msg) => Ok(Some((msg, passed))),
+        Err(e) => {
+            if let Some(passed) = passed {
+                close(passed).ok();
+            }
+            Err(e.into())
+        }
+    }
+}
+
+fn recv_blocking(fd: RawFd) -> Result<(Message, Option<RawFd>), SetupError> {
+    match recv_with_fd(fd, MsgFlags::empty())? {
+        Some(received) => Ok(received),
+        None => {
+            log::error!("Socket closed by the other end");
+            Err(ErrCode::SocketError(2).into())
+        }
+    }
+}
+
+// Turns an error reported by the other end into one of ours, the child's
+// setup errors are passed on as they are
+fn check_error(msg: Message) -> Result<Message, ErrCode> {
+    match msg {
+        Message::Error { code, message } => {
+            log::error!("Other end of the socket failed: {} ({})", message, code);
+            Err(ErrCode::SocketError(7))
+        }
+        Message::SetupFailed {
+            stage,
+            error,
+            errno,
+        } => {
+            match errno {
+                Some(errno) => log::error!(
+                    "Container setup failed at stage {:?}: {} ({})",
+                    stage,
+                    error,
+                    Errno::from_i32(errno)
+                ),
+                None => log::error!("Container setup failed at stage {:?}: {}", stage, error),
+            }
+            Err(error)
+        }
+        msg => Ok(msg),
+    }
+}
+
+fn unexpected(msg: &Message, expected: &str) -> ErrCode {
+    log::error!("Received {:?} from socket, expected {}", msg, expected);
+    ErrCode::SocketError(8)
+}
+
+pub fn send_message(fd: RawFd, msg: &Message) -> Result<(), SetupError> {
+    send_with_fd(fd, msg, None)
+}
+
+fn recv_checked(fd: RawFd) -> Result<Message, SetupError> {
+    let (msg, passed) = recv_blocking(fd)?;
+    if let Some(passed) = passed {
+        log::warn!("Closing file descriptor sent along {:?}", msg);
+        close(passed).ok();
+    }
+    Ok(check_error(msg)?)
+}
+
+pub fn recv_message(fd: RawFd) -> Result<Message, ErrCode> {
+    Ok(recv_checked(fd)?)
+}
+
+pub fn send_stage(fd: RawFd, stage: Stage) -> Result<(), ErrCode> {
+    Ok(send_message(fd, &Message::Stage(stage))?)
+}
+
+// Blocks until the other end is done with the stage
+pub fn wait_stage(fd: RawFd, stage: Stage) -> Result<(), SetupError> {
+    match recv_checked(fd)? {
+        Message::Stage(s) if s == stage => Ok(()),
+        msg => Err(unexpected(&msg, &format!("stage {:?}", stage)).into()),
+    }
+}
+
+// Tells the other end we gave up because of err, it fails instead of
+// waiting for a message that will never come
+pub fn send_error(fd: RawFd, err: &ErrCode, message: &str) {
+    let msg = Message::Error {
+        code: err.to_string(),
+        message: message.to_string(),
+    };
+    send_message(fd, &msg).ok();
+}
+
+// Passes a file descriptor to the other end of the socket (SCM_RIGHTS)
+pub fn send_fd(fd: RawFd, kind: FdKind, passed: RawFd) -> Result<(), SetupError> {
+    send_with_fd(fd, &Message::Fd(kind), Some(passed))
+}
+
+pub fn recv_fd(fd: RawFd, kind: FdKind) -> Result<RawFd, ErrCode> {
+    let (msg, passed) = recv_blocking(fd)?;
+    match (check_error(msg), passed) {
+        (Ok(Message::Fd(k)), Some(passed)) if k == kind => Ok(passed),
+        (msg, passed) => {
+            if let Some(passed) = passed {
+                close(passed).ok();
+            }
+            let msg = msg?;
+            if msg == Message::Fd(kind) {
+                log::error!("No file descriptor in message received from socket");
+                return Err(ErrCode::SocketError(5));
+            }
+            Err(unexpected(&msg, &format!("{:?} file descriptor", kind)))
+        }
+    }
+}
+
+// Error the child reported before exiting, to be read once it is gone as
+// anything it sent is queued by then
+pub fn recv_setup_error(fd: RawFd) -> Option<ErrCode> {
+    match recv_with_fd(fd, MsgFlags::MSG_DONTWAIT) {
+        Ok(Some((msg, passed))) => {
+            if let Some(passed) = passed {
+                close(passed).ok();
+            }
+            check_error(msg).err()
+        }
+        Ok(None) => None,
+        Err(e) => Some(e.code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let (parent, child) = generate_socket_pair().unwrap();
+        send_message(child, &Message::Pid(1)).unwrap();
+        send_stage(parent, Stage::Cgroup).unwrap();
+        assert_eq!(recv_message(parent).unwrap(), Message::Pid(1));
+        assert!(wait_stage(child, Stage::Cgroup).is_ok());
+
+        send_fd(child, FdKind::Tap, child).unwrap();
+        let passed = recv_fd(parent, FdKind::Tap).unwrap();
+        assert!(passed != child);
+        close(passed).unwrap();
+
+        send_error(parent, &ErrCode::NamespaceError(4), "uid map");
+        assert!(matches!(
+            wait_stage(child, Stage::UidMap),
+            Err(SetupError {
+                code: ErrCode::SocketError(7),
+                errno: None
+            })
+        ));
+        send_stage(parent, Stage::UidMap).unwrap();
+        assert!(matches!(
+            wait_stage(child, Stage::Cgroup),
+            Err(SetupError {
+                code: ErrCode::SocketError(8),
+                errno: None
+            })
+        ));
+        close(parent).unwrap();
+        assert!(recv_message(child).is_err());
+        close(child).unwrap();
+    }
+
+    #[test]
+    fn setup_error_passed_on() {
+        let (parent, child) = generate_socket_pair().unwrap();
+        assert_eq!(recv_setup_error(parent), None);
+        let report = Message::SetupFailed {
+            stage: SetupStage::Mounts,
+            error: ErrCode::InvalidArgument("device"),
+            errno: Some(Errno::ENOENT as i32),
+        };
+        send_message(child, &report).unwrap();
+        assert_eq!(
+            recv_setup_error(parent),
+            Some(ErrCode::InvalidArgument("device"))
+        );
+        send_message(child, &report).unwrap();
+        assert_eq!(
+            recv_message(parent),
+            Err(ErrCode::InvalidArgument("device"))
+        );
+        close(child).unwrap();
+        assert_eq!(recv_setup_error(parent), None);
+        close(parent).unwrap();
     }
-    Ok(data[0] == 1)
 }
@@ -10,21 +10,54 @@ mod namespaces;
 mod capabilities;
 mod syscalls;
 mod resources;
+mod network;
+mod slirp;
+mod seccomp_profile;
+#[cfg(seccomp_notify)]
+mod supervisor;
+#[cfg(seccomp_notify)]
+mod notify_policy;
+#[cfg(seccomp_notify)]
+mod seccomp_audit;
+mod state;
+mod stats;
+mod events;
+mod devices;
+mod environment;
+mod users;
 
+use cli::Command;
 use errors::exit_with_return_code;
 
 #[macro_use]
 extern crate scan_fmt;
 
 fn main() {
-    let args = cli::parse_args().expect("Failed to parse arguments");
+    match cli::parse_args().expect("Failed to parse arguments") {
+        Command::Run(args) => {
+            setup_log(args.debug);
+            log::info!("{:?}", args);
+            exit_with_return_code(container::start(args));
+        }
+        Command::Stats(args) => {
+            setup_log(args.debug);
+            exit_with_return_code(stats::show(args));
+        }
+        Command::Pause(args) => {
+            setup_log(args.debug);
+            exit_with_return_code(container::set_paused(&args.id, true));
+        }
+        Command::Resume(args) => {
+            setup_log(args.debug);
+            exit_with_return_code(container::set_paused(&args.id, false));
+        }
+    }
+}
 
-    if args.debug {
+fn setup_log(debug: bool) {
+    if debug {
         cli::setup_log(log::LevelFilter::Debug)
     } else {
         cli::setup_log(log::LevelFilter::Info)
     }
-    log::info!("{:?}", args);
-
-    exit_with_return_code(container::start(args));
 }
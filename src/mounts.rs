@@ -1,11 +1,16 @@
-use crate::errors::ErrCode;
+use crate::devices::DeviceMapping;
+use crate::errors::{io_errno, ErrCode, SetupError};
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::unistd::{chdir, pivot_root};
 use rand::Rng;
-use std::fs::remove_dir;
+use std::fs::{remove_dir, File};
 use std::{fs::create_dir_all, path::PathBuf};
 
-pub fn set_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, PathBuf)>) -> Result<(), ErrCode> {
+pub fn set_mountpoint(
+    mount_dir: &PathBuf,
+    addpaths: &Vec<(PathBuf, PathBuf)>,
+    devices: &[DeviceMapping],
+) -> Result<(), SetupError> {
     log::debug!("Setting mount point");
 
     // First we (privately) mount / within the container...
@@ -47,6 +52,28 @@ pub fn set_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, PathBuf)>) -
         )?;
     }
 
+    // Device nodes can't be created from the user namespace, the host ones
+    // are bind mounted over empty files
+    log::debug!("Mounting devices...");
+    for device in devices.iter() {
+        let outpath = new_root.join(device.container.strip_prefix("/").unwrap());
+        if let Some(parent) = outpath.parent() {
+            create_directory(&parent.to_path_buf())?;
+        }
+        // An existing node would be opened, and maybe denied by the device rules
+        if !outpath.exists() {
+            if let Err(e) = File::create(&outpath) {
+                log::error!("Cannot create {}: {}", outpath.display(), e);
+                return Err(ErrCode::MountError(6).caused_by(io_errno(&e)));
+            }
+        }
+        mount_directory(
+            Some(&device.host),
+            &outpath,
+            vec![MsFlags::MS_PRIVATE, MsFlags::MS_BIND],
+        )?;
+    }
+
     // ...finally we will do a root pivot
     // See: https://man7.org/linux/man-pages/man2/pivot_root.2.html
 
@@ -60,16 +87,16 @@ pub fn set_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, PathBuf)>) -
     let put_old = new_root.join(PathBuf::from(old_root_tail.clone()));
 
     create_directory(&put_old)?;
-    if let Err(_) = pivot_root(&new_root, &put_old) {
-        return Err(ErrCode::MountError(4));
+    if let Err(e) = pivot_root(&new_root, &put_old) {
+        return Err(ErrCode::MountError(4).caused_by(e));
     }
 
     // Lastly, to achieve isolation from host system, must unmount old root
     log::debug!("Unmounting old root");
     let old_root = PathBuf::from(format!("/{}", old_root_tail));
 
-    if let Err(_) = chdir(&PathBuf::from("/")) {
-        return Err(ErrCode::MountError(5));
+    if let Err(e) = chdir(&PathBuf::from("/")) {
+        return Err(ErrCode::MountError(5).caused_by(e));
     }
     unmount_path(&old_root)?;
     delete_dir(&old_root)?;
@@ -86,7 +113,7 @@ pub fn mount_directory(
     path: Option<&PathBuf>,
     mount_point: &PathBuf,
     flags: Vec<MsFlags>,
-) -> Result<(), ErrCode> {
+) -> Result<(), SetupError> {
     let mut ms_flags = MsFlags::empty();
 
     for f in flags.iter() {
@@ -111,7 +138,7 @@ pub fn mount_directory(
             } else {
                 log::error!("Cannot remount {}: {}", mount_point.to_str().unwrap(), e);
             }
-            Err(ErrCode::MountError(3))
+            Err(ErrCode::MountError(3).caused_by(e))
         }
     }
 }
@@ -132,27 +159,27 @@ pub fn random_string(n: usize) -> String {
     name
 }
 
-pub fn create_directory(path: &PathBuf) -> Result<(), ErrCode> {
+pub fn create_directory(path: &PathBuf) -> Result<(), SetupError> {
     match create_dir_all(path) {
         Err(e) => {
             log::error!("Cannot create directory {}: {}", path.to_str().unwrap(), e);
-            Err(ErrCode::MountError(2))
+            Err(ErrCode::MountError(2).caused_by(io_errno(&e)))
         }
         Ok(_) => Ok(()),
     }
 }
 
-pub fn unmount_path(path: &PathBuf) -> Result<(), ErrCode> {
+pub fn unmount_path(path: &PathBuf) -> Result<(), SetupError> {
     match umount2(path, MntFlags::MNT_DETACH) {
         Ok(_) => Ok(()),
         Err(e) => {
             log::error!("Unable to unmount {}: {}", path.to_str().unwrap(), e);
-            Err(ErrCode::MountError(0))
+            Err(ErrCode::MountError(0).caused_by(e))
         }
     }
 }
 
-pub fn delete_dir(path: &PathBuf) -> Result<(), ErrCode> {
+pub fn delete_dir(path: &PathBuf) -> Result<(), SetupError> {
     match remove_dir(path.as_path()) {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -161,7 +188,7 @@ pub fn delete_dir(path: &PathBuf) -> Result<(), ErrCode> {
                 path.to_str().unwrap(),
                 e
             );
-            Err(ErrCode::MountError(1))
+            Err(ErrCode::MountError(1).caused_by(io_errno(&e)))
         }
     }
 }
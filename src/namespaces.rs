@@ -1,30 +1,60 @@
 use std::fs::File;
 use std::io::Write;
 use std::os::unix::io::RawFd;
+use std::str::FromStr;
 
+use capctl::prctl::set_keepcaps;
+use nix::errno::Errno;
 use nix::sched::{unshare, CloneFlags};
 use nix::unistd::Pid;
 use nix::unistd::{Gid, Uid};
 use nix::unistd::{setgroups, setresuid, setresgid};
 
-use crate::errors::ErrCode;
-use crate::ipc::{send_boolean, recv_boolean};
+use crate::errors::{ErrCode, SetupError};
+use crate::users::User;
+use crate::ipc::{recv_message, send_error, send_message, send_stage, wait_stage, Message, Stage};
+
+// --userns-range HOST_ID:COUNT, the host ids the ids of the container are
+// mapped to, container ids from 0 to COUNT - 1 are usable. By default
+// 10000:65536, up to nobody (65534) that images commonly run as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsernsRange {
+    pub host_start: u32,
+    pub count: u32,
+}
+
+impl FromStr for UsernsRange {
+    type Err = ErrCode;
+
+    // Root of the host is never mapped, the container's root would be it
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = ErrCode::InvalidArgument("userns-range");
+        let (start, count) = s.split_once(':').ok_or(invalid.clone())?;
+        let host_start: u32 = start.parse().map_err(|_| invalid.clone())?;
+        let count: u32 = count.parse().map_err(|_| invalid.clone())?;
+        if host_start == 0 || count == 0 || host_start.checked_add(count - 1).is_none() {
+            return Err(invalid);
+        }
+        Ok(UsernsRange { host_start, count })
+    }
+}
 
-const USERNS_OFFSET: u64 = 10_000;
-const USERNS_COUNT: u64 = 2000;
+impl UsernsRange {
+    pub fn contains(&self, id: u32) -> bool {
+        id < self.count
+    }
+}
 
-pub fn userns(fd: RawFd, uid: u32) -> Result<(), ErrCode> {
-    log::debug!("Setting up user namespace with UID {}", uid);
+pub fn userns(fd: RawFd) -> Result<(), SetupError> {
+    log::debug!("Setting up user namespace");
 
     let has_userns = match unshare(CloneFlags::CLONE_NEWUSER) {
         Ok(_) => true,
         Err(_) => false,
     };
-    send_boolean(fd, has_userns)?;
+    send_message(fd, &Message::UserNamespace(has_userns))?;
 
-    if recv_boolean(fd)? {
-        return Err(ErrCode::NamespaceError(0));
-    }
+    wait_stage(fd, Stage::UidMap)?;
 
     if has_userns {
         log::info!("User namespace set up");
@@ -32,49 +62,84 @@ pub fn userns(fd: RawFd, uid: u32) -> Result<(), ErrCode> {
         log::info!("User namespace not supported, continuing...");
     }
 
-    log::debug!("Switching to uid {} / gid {}...", uid, uid);
-    let gid = Gid::from_raw(uid);
-    let uid = Uid::from_raw(uid);
+    Ok(())
+}
+
+pub fn switch_user(user: &User) -> Result<(), SetupError> {
+    log::debug!(
+        "Switching to uid {} / gid {} (groups {:?})...",
+        user.uid,
+        user.gid,
+        user.groups
+    );
+    let groups: Vec<Gid> = user.groups.iter().map(|g| Gid::from_raw(*g)).collect();
+    let gid = Gid::from_raw(user.gid);
+    let uid = Uid::from_raw(user.uid);
+
+    if let Err(e) = setgroups(&groups) {
+        return Err(ErrCode::NamespaceError(1).caused_by(e));
+    }
 
-    if let Err(_) = setgroups(&[gid]) {
-        return Err(ErrCode::NamespaceError(1));
+    if let Err(e) = setresgid(gid, gid, gid) {
+        return Err(ErrCode::NamespaceError(2).caused_by(e));
     }
 
-    if let Err(_) = setresgid(gid, gid, gid) {
-        return Err(ErrCode::NamespaceError(2));
+    // Leaving uid 0 would clear the permitted set, keep it so capabilities
+    // can still be raised (and made ambient) for a non-root workload
+    if let Err(e) = set_keepcaps(true) {
+        return Err(ErrCode::NamespaceError(8).caused_by(Errno::from_i32(e.code())));
     }
 
-    if let Err(_) = setresuid(uid, uid, uid) {
-        return Err(ErrCode::NamespaceError(3));
+    if let Err(e) = setresuid(uid, uid, uid) {
+        return Err(ErrCode::NamespaceError(3).caused_by(e));
+    }
+
+    if let Err(e) = set_keepcaps(false) {
+        return Err(ErrCode::NamespaceError(9).caused_by(Errno::from_i32(e.code())));
     }
 
     Ok(())
 }
 
 
-pub fn handle_child_uid_map(pid: Pid, fd: RawFd) -> Result<(), ErrCode> {
-    if recv_boolean(fd)? {
-
-        if let Ok(mut uid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "uid_map")) {
-            if let Err(_) = uid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes()) {
-                return Err(ErrCode::NamespaceError(4));
-            }
-        } else {
-            return Err(ErrCode::NamespaceError(5));
+pub fn handle_child_uid_map(pid: Pid, fd: RawFd, range: UsernsRange) -> Result<(), ErrCode> {
+    let has_userns = match recv_message(fd)? {
+        Message::UserNamespace(has_userns) => has_userns,
+        msg => {
+            log::error!("Received {:?} from child, expected its user namespace", msg);
+            return Err(ErrCode::NamespaceError(0));
         }
-
-        if let Ok(mut gid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "gid_map")) {
-            if let Err(_) = gid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes()) {
-                return Err(ErrCode::NamespaceError(6));
-            }
-        } else {
-            return Err(ErrCode::NamespaceError(7));
+    };
+    if has_userns {
+        if let Err(e) = write_uid_map(pid, range) {
+            send_error(fd, &e, "Cannot write the uid / gid maps of the child");
+            return Err(e);
         }
-
     } else {
         log::info!("No user namespace set up from child process");
     }
 
     log::debug!("Child UID/GID map done, sending signal to child to continue...");
-    send_boolean(fd, false)
+    send_stage(fd, Stage::UidMap)
+}
+
+fn write_uid_map(pid: Pid, range: UsernsRange) -> Result<(), ErrCode> {
+    let map = format!("0 {} {}", range.host_start, range.count);
+    if let Ok(mut uid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "uid_map")) {
+        if let Err(_) = uid_map.write_all(map.as_bytes()) {
+            return Err(ErrCode::NamespaceError(4));
+        }
+    } else {
+        return Err(ErrCode::NamespaceError(5));
+    }
+
+    if let Ok(mut gid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "gid_map")) {
+        if let Err(_) = gid_map.write_all(map.as_bytes()) {
+            return Err(ErrCode::NamespaceError(6));
+        }
+    } else {
+        return Err(ErrCode::NamespaceError(7));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,234 @@
+use std::ffi::CString;
+use std::fmt;
+use std::mem::zeroed;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+
+use libc::{c_char, c_ulong, ifreq, rtentry, sockaddr, sockaddr_in};
+use nix::fcntl::{open, OFlag};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+
+use crate::errors::{ErrCode, SetupError};
+use crate::ipc::{send_fd, FdKind};
+
+// Addressing of the user-mode network, same layout as slirp4netns
+pub const SLIRP_GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const SLIRP_DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+pub const SLIRP_GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+pub const SLIRP_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+pub const SLIRP_MTU: usize = 1500;
+
+const TAP_NAME: &str = "tap0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkMode {
+    // Empty network namespace, only an (unconfigured) loopback
+    None,
+    // Tap device served by a userspace TCP/IP stack in the parent
+    Slirp,
+}
+
+impl FromStr for NetworkMode {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NetworkMode::None),
+            "slirp" => Ok(NetworkMode::Slirp),
+            _ => Err(ErrCode::InvalidArgument("network")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortForward {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+// Parses HOST_PORT:CONTAINER_PORT[/tcp|/udp]
+impl FromStr for PortForward {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some(_) => return Err(ErrCode::InvalidArgument("publish")),
+            None => (s, Protocol::Tcp),
+        };
+        let (host, container) = ports
+            .split_once(':')
+            .ok_or(ErrCode::InvalidArgument("publish"))?;
+        let parse_port = |p: &str| match p.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(port),
+            _ => Err(ErrCode::InvalidArgument("publish")),
+        };
+        Ok(PortForward {
+            host_port: parse_port(host)?,
+            container_port: parse_port(container)?,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let proto = match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        write!(f, "{}:{}/{}", self.host_port, self.container_port, proto)
+    }
+}
+
+// Has to run before the root pivot, as /dev/net/tun is taken from the host
+pub fn setup_network(fd: RawFd, mode: NetworkMode) -> Result<(), SetupError> {
+    if mode == NetworkMode::None {
+        return Ok(());
+    }
+
+    log::debug!("Setting up user-mode networking");
+    let tap = create_tap(TAP_NAME)?;
+
+    let sock = match socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        Ok(s) => s,
+        Err(e) => return Err(ErrCode::NetworkError(1).caused_by(e)),
+    };
+
+    let res = configure_interfaces(sock);
+    close(sock).ok();
+    res?;
+
+    // The parent serves the tap device, we don't need it anymore
+    send_fd(fd, FdKind::Tap, tap)?;
+    if let Err(e) = close(tap) {
+        return Err(ErrCode::NetworkError(0).caused_by(e));
+    }
+
+    log::debug!(
+        "Interface {} up with address {}, gateway {}",
+        TAP_NAME,
+        SLIRP_GUEST,
+        SLIRP_GATEWAY
+    );
+    Ok(())
+}
+
+fn configure_interfaces(sock: RawFd) -> Result<(), SetupError> {
+    set_interface_up(sock, "lo")?;
+    set_interface_addr(sock, TAP_NAME, libc::SIOCSIFADDR, SLIRP_GUEST)?;
+    set_interface_addr(sock, TAP_NAME, libc::SIOCSIFNETMASK, SLIRP_NETMASK)?;
+    set_interface_up(sock, TAP_NAME)?;
+    add_default_route(sock, SLIRP_GATEWAY)
+}
+
+fn create_tap(name: &str) -> Result<RawFd, SetupError> {
+    let tap = match open(
+        "/dev/net/tun",
+        OFlag::O_RDWR | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => fd,
+        Err(e) => {
+            log::error!("Cannot open /dev/net/tun: {}", e);
+            return Err(ErrCode::NetworkError(2).caused_by(e));
+        }
+    };
+
+    let mut req = new_ifreq(name)?;
+    req.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+    if let Err(e) = ioctl(tap, libc::TUNSETIFF as c_ulong, &mut req) {
+        log::error!("Cannot create tap device {}: {}", name, e);
+        close(tap).ok();
+        return Err(ErrCode::NetworkError(3).caused_by(e));
+    }
+    Ok(tap)
+}
+
+fn set_interface_up(sock: RawFd, name: &str) -> Result<(), SetupError> {
+    let mut req = new_ifreq(name)?;
+    if let Err(e) = ioctl(sock, libc::SIOCGIFFLAGS, &mut req) {
+        log::error!("Cannot get the flags of interface {}: {}", name, e);
+        return Err(ErrCode::NetworkError(4).caused_by(e));
+    }
+    unsafe {
+        req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    }
+    if let Err(e) = ioctl(sock, libc::SIOCSIFFLAGS, &mut req) {
+        log::error!("Cannot bring interface {} up: {}", name, e);
+        return Err(ErrCode::NetworkError(4).caused_by(e));
+    }
+    Ok(())
+}
+
+fn set_interface_addr(
+    sock: RawFd,
+    name: &str,
+    request: c_ulong,
+    addr: Ipv4Addr,
+) -> Result<(), SetupError> {
+    let mut req = new_ifreq(name)?;
+    req.ifr_ifru.ifru_addr = to_sockaddr(addr);
+    if let Err(e) = ioctl(sock, request, &mut req) {
+        log::error!("Cannot set address {} on {}: {}", addr, name, e);
+        return Err(ErrCode::NetworkError(5).caused_by(e));
+    }
+    Ok(())
+}
+
+fn add_default_route(sock: RawFd, gateway: Ipv4Addr) -> Result<(), SetupError> {
+    let mut route: rtentry = unsafe { zeroed() };
+    route.rt_dst = to_sockaddr(Ipv4Addr::UNSPECIFIED);
+    route.rt_genmask = to_sockaddr(Ipv4Addr::UNSPECIFIED);
+    route.rt_gateway = to_sockaddr(gateway);
+    route.rt_flags = libc::RTF_UP | libc::RTF_GATEWAY;
+    if let Err(e) = ioctl(sock, libc::SIOCADDRT, &mut route) {
+        log::error!("Cannot add default route via {}: {}", gateway, e);
+        return Err(ErrCode::NetworkError(6).caused_by(e));
+    }
+    Ok(())
+}
+
+fn new_ifreq(name: &str) -> Result<ifreq, ErrCode> {
+    let cname = match CString::new(name) {
+        Ok(c) if name.len() < libc::IFNAMSIZ => c,
+        _ => return Err(ErrCode::InvalidArgument("interface name")),
+    };
+    let mut req: ifreq = unsafe { zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(cname.as_bytes()) {
+        *dst = *src as c_char;
+    }
+    Ok(req)
+}
+
+fn to_sockaddr(addr: Ipv4Addr) -> sockaddr {
+    let mut sin: sockaddr_in = unsafe { zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    unsafe { *(&sin as *const sockaddr_in as *const sockaddr) }
+}
+
+fn ioctl<T>(fd: RawFd, request: c_ulong, arg: &mut T) -> Result<(), nix::Error> {
+    let res = unsafe { libc::ioctl(fd, request as _, arg as *mut T) };
+    if res < 0 {
+        Err(nix::Error::last())
+    } else {
+        Ok(())
+    }
+}
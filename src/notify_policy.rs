@@ -0,0 +1,354 @@
+use std::ffi::CString;
+use std::fs::{read_to_string, File};
+use std::io::BufReader;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use libseccomp::{notify_id_valid, ScmpNotifReq, ScmpNotifResp, ScmpNotifRespFlags};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, fork, ForkResult};
+use serde::Deserialize;
+
+use crate::errors::ErrCode;
+use crate::seccomp_profile::SyscallArg;
+use crate::supervisor::syscall_name;
+
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const PATH_MAX: usize = 4096;
+
+// Syscalls the supervisor knows how to perform on behalf of the container
+const EMULATED_SYSCALLS: [&str; 2] = ["mknod", "mknodat"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    // Let the kernel run the syscall with the container's privileges
+    Allow,
+    // Fail the syscall with errnoRet
+    Deny,
+    // Run it from the supervisor, or just return returnValue
+    Emulate,
+}
+
+// Decisions for the syscalls sent to the supervisor, the first matching rule
+// wins and unmatched syscalls are denied
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NotifyPolicy {
+    #[serde(default)]
+    pub default_errno_ret: Option<i32>,
+    pub rules: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PolicyRule {
+    pub names: Vec<String>,
+    pub decision: Decision,
+    // Same comparisons as in seccomp profiles
+    #[serde(default)]
+    pub args: Vec<SyscallArg>,
+    // Arguments pointing to a string, read from the memory of the caller.
+    // Not for allow rules: the kernel reads the string again once the call
+    // continues, the caller can change it in between.
+    #[serde(default)]
+    pub strings: Vec<StringArg>,
+    #[serde(default)]
+    pub errno_ret: Option<i32>,
+    #[serde(default)]
+    pub return_value: Option<i64>,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StringArg {
+    pub index: u32,
+    pub values: Vec<String>,
+}
+
+fn invalid(what: String) -> ErrCode {
+    log::error!("Invalid seccomp notification policy: {}", what);
+    ErrCode::InvalidArgument("seccomp-notify")
+}
+
+impl NotifyPolicy {
+    pub fn load(path: &Path) -> Result<NotifyPolicy, ErrCode> {
+        log::debug!("Loading seccomp notification policy {}", path.display());
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot open notification policy {}: {}", path.display(), e);
+                return Err(ErrCode::InvalidArgument("seccomp-notify"));
+            }
+        };
+        let policy: NotifyPolicy = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(policy) => policy,
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<(), ErrCode> {
+        for rule in self.rules.iter() {
+            if rule.names.is_empty() {
+                return Err(invalid("rule without syscall names".to_string()));
+            }
+            for arg in rule.args.iter() {
+                if arg.index > 5 || !OPS.contains(&arg.op.as_str()) {
+                    return Err(invalid(format!("argument {} {}", arg.index, arg.op)));
+                }
+            }
+            if rule.strings.iter().any(|s| s.index > 5) {
+                return Err(invalid("string argument index".to_string()));
+            }
+            if rule.decision == Decision::Allow && !rule.strings.is_empty() {
+                return Err(invalid(format!("strings in allow rule for {:?}", rule.names)));
+            }
+            if rule.errno_ret.iter().chain(&self.default_errno_ret).any(|e| *e <= 0) {
+                return Err(invalid("errnoRet must be positive".to_string()));
+            }
+            let emulated = rule.names.iter().all(|n| EMULATED_SYSCALLS.contains(&n.as_str()));
+            if rule.decision == Decision::Emulate && !emulated && rule.return_value.is_none() {
+                return Err(invalid(format!("no emulation for {:?}", rule.names)));
+            }
+        }
+        Ok(())
+    }
+
+    // Syscalls the seccomp filter has to send to the supervisor
+    pub fn syscalls(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.rules.iter().flat_map(|r| r.names.clone()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn decide(&self, listener: RawFd, req: &ScmpNotifReq) -> ScmpNotifResp {
+        let name = syscall_name(req);
+        let call = format!("{}({:#x}, {:#x}, {:#x}, ...) from pid {}", name,
+            req.data.args[0], req.data.args[1], req.data.args[2], req.pid);
+
+        let mut strings = StringCache::new(req.pid);
+        let rule = self.rules.iter().find(|rule| {
+            rule.names.contains(&name)
+                && rule.args.iter().all(|arg| arg_matches(arg, req.data.args[arg.index as usize]))
+                && rule.strings.iter().all(|s| {
+                    let value = strings.get(s.index, req.data.args[s.index as usize]);
+                    value.is_some_and(|v| s.values.contains(&v))
+                })
+        });
+        // Whatever was read from /proc/<pid> is only meaningful if the caller
+        // still is the same process, waiting on this notification
+        if notify_id_valid(listener, req.id).is_err() {
+            return ScmpNotifResp::new_error(req.id, -ENOENT, ScmpNotifRespFlags::empty());
+        }
+
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                let errno = self.default_errno_ret.unwrap_or(EPERM);
+                log::info!("seccomp: denied {} (no rule, errno {})", call, errno);
+                return ScmpNotifResp::new_error(req.id, -errno, ScmpNotifRespFlags::empty());
+            }
+        };
+
+        // The rule comment tells in the log why a call was let through or not
+        let why = match &rule.comment {
+            Some(comment) => format!(" [{}]", comment),
+            None => String::new(),
+        };
+        match rule.decision {
+            Decision::Allow => {
+                log::info!("seccomp: allowed {}{}", call, why);
+                ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::empty())
+            }
+            Decision::Deny => {
+                let errno = rule.errno_ret.unwrap_or(EPERM);
+                log::info!("seccomp: denied {} (errno {}){}", call, errno, why);
+                ScmpNotifResp::new_error(req.id, -errno, ScmpNotifRespFlags::empty())
+            }
+            Decision::Emulate => {
+                let result = match rule.return_value {
+                    Some(val) => Ok(val),
+                    None => emulate(&name, listener, req).map(|_| 0),
+                };
+                match result {
+                    Ok(val) => {
+                        log::info!("seccomp: emulated {} (returned {}){}", call, val, why);
+                        ScmpNotifResp::new_val(req.id, val, ScmpNotifRespFlags::empty())
+                    }
+                    Err(e) => {
+                        log::info!("seccomp: emulated {} (failed: {}){}", call, e, why);
+                        ScmpNotifResp::new_error(req.id, -(e as i32), ScmpNotifRespFlags::empty())
+                    }
+                }
+            }
+        }
+    }
+}
+
+const OPS: [&str; 7] = [
+    "SCMP_CMP_NE",
+    "SCMP_CMP_LT",
+    "SCMP_CMP_LE",
+    "SCMP_CMP_EQ",
+    "SCMP_CMP_GE",
+    "SCMP_CMP_GT",
+    "SCMP_CMP_MASKED_EQ",
+];
+
+fn arg_matches(arg: &SyscallArg, actual: u64) -> bool {
+    match arg.op.as_str() {
+        "SCMP_CMP_NE" => actual != arg.value,
+        "SCMP_CMP_LT" => actual < arg.value,
+        "SCMP_CMP_LE" => actual <= arg.value,
+        "SCMP_CMP_EQ" => actual == arg.value,
+        "SCMP_CMP_GE" => actual >= arg.value,
+        "SCMP_CMP_GT" => actual > arg.value,
+        "SCMP_CMP_MASKED_EQ" => actual & arg.value == arg.value_two,
+        _ => false,
+    }
+}
+
+// String arguments of the caller, read once from /proc/<pid>/mem
+struct StringCache {
+    pid: u32,
+    values: [Option<Option<String>>; 6],
+}
+
+impl StringCache {
+    fn new(pid: u32) -> StringCache {
+        StringCache {
+            pid,
+            values: Default::default(),
+        }
+    }
+
+    fn get(&mut self, index: u32, addr: u64) -> Option<String> {
+        let pid = self.pid;
+        self.values[index as usize]
+            .get_or_insert_with(|| read_string(pid, addr))
+            .clone()
+    }
+}
+
+fn read_string(pid: u32, addr: u64) -> Option<String> {
+    let mem = File::open(format!("/proc/{}/mem", pid)).ok()?;
+    let mut buf = vec![0; PATH_MAX];
+    let len = mem.read_at(&mut buf, addr).ok()?;
+    let end = buf[..len].iter().position(|b| *b == 0)?;
+    buf.truncate(end);
+    String::from_utf8(buf).ok()
+}
+
+fn emulate(name: &str, listener: RawFd, req: &ScmpNotifReq) -> Result<(), Errno> {
+    let args = req.data.args;
+    let caller = Caller {
+        listener,
+        id: req.id,
+        pid: req.pid,
+    };
+    match name {
+        "mknod" => mknod(&caller, None, args[0], args[1], args[2]),
+        "mknodat" => mknod(&caller, Some(args[0] as i32), args[1], args[2], args[3]),
+        _ => Err(Errno::ENOSYS),
+    }
+}
+
+// Process waiting on a notification
+struct Caller {
+    listener: RawFd,
+    id: u64,
+    pid: u32,
+}
+
+impl Caller {
+    // The pid may have been reused once the notification is gone, what was
+    // read or opened through /proc/<pid> is then someone else's
+    fn check(&self) -> Result<(), Errno> {
+        notify_id_valid(self.listener, self.id).map_err(|_| Errno::ENOENT)
+    }
+}
+
+// Creates the node from a child process chrooted in the container's root,
+// relative paths are resolved from the caller's cwd or dirfd
+fn mknod(caller: &Caller, dirfd: Option<i32>, path: u64, mode: u64, dev: u64) -> Result<(), Errno> {
+    let path = read_string(caller.pid, path).ok_or(Errno::EFAULT)?;
+    caller.check()?;
+    let path = CString::new(path).map_err(|_| Errno::EINVAL)?;
+    let (uid, gid, umask) = caller_credentials(caller.pid).ok_or(Errno::ESRCH)?;
+    caller.check()?;
+
+    let proc_dir = format!("/proc/{}", caller.pid);
+    let dir = match dirfd {
+        Some(fd) if fd != libc::AT_FDCWD => format!("{}/fd/{}", proc_dir, fd),
+        _ => format!("{}/cwd", proc_dir),
+    };
+    let flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    let root = open(format!("{}/root", proc_dir).as_str(), flags, Mode::empty())?;
+    let dir = match open(dir.as_str(), flags, Mode::empty()) {
+        Ok(dir) => dir,
+        Err(e) => {
+            close(root).ok();
+            return Err(e);
+        }
+    };
+    if let Err(e) = caller.check() {
+        close(root).ok();
+        close(dir).ok();
+        return Err(e);
+    }
+
+    // Only async-signal-safe calls in the child, the parent is multithreaded
+    let res = match unsafe { fork() } {
+        Ok(ForkResult::Child) => unsafe {
+            let ret = if libc::fchdir(root) < 0
+                || libc::chroot(c".".as_ptr()) < 0
+            {
+                -1
+            } else {
+                libc::umask(umask);
+                if libc::mknodat(dir, path.as_ptr(), mode as libc::mode_t, dev as libc::dev_t) < 0
+                    || libc::fchownat(dir, path.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) < 0
+                {
+                    -1
+                } else {
+                    0
+                }
+            };
+            libc::_exit(if ret < 0 { *libc::__errno_location() } else { 0 })
+        },
+        Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+            Ok(WaitStatus::Exited(_, errno)) => Err(Errno::from_i32(errno)),
+            _ => Err(Errno::EIO),
+        },
+        Err(e) => Err(e),
+    };
+    close(root).ok();
+    close(dir).ok();
+    res
+}
+
+// Filesystem uid / gid (as seen from the host) and umask of the caller
+fn caller_credentials(pid: u32) -> Option<(libc::uid_t, libc::gid_t, libc::mode_t)> {
+    let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+            .map(|v| v.split_whitespace().collect::<Vec<&str>>())
+    };
+    let uid = field("Uid")?.get(3)?.parse().ok()?;
+    let gid = field("Gid")?.get(3)?.parse().ok()?;
+    let umask = libc::mode_t::from_str_radix(field("Umask")?.first()?, 8).ok()?;
+    Some((uid, gid, umask))
+}
@@ -1,51 +1,610 @@
-use std::fs::{canonicalize, remove_dir};
+use std::fs::read_to_string;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use cgroups_rs::{cgroup_builder::CgroupBuilder, hierarchies::V2, CgroupPid, MaxValue};
-use nix::unistd::Pid;
-use rlimit::{setrlimit, Resource};
+use cgroups_rs::hierarchies::{V1, V2};
+use cgroups_rs::cpuset::CpuSetController;
+use cgroups_rs::devices::DevicesController;
+use cgroups_rs::freezer::{FreezerController, FreezerState};
+use cgroups_rs::{
+    BlkIoDeviceThrottleResource, BlkIoResources, Cgroup, CgroupPid, CpuResources, Hierarchy,
+    MaxValue, MemoryResources, PidResources, Resources,
+};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::{major, minor, stat, Mode, SFlag};
+use nix::sys::statfs::{statfs, CGROUP2_SUPER_MAGIC};
+use nix::unistd::{close, Pid};
+use rlimit::{setrlimit, Resource, INFINITY};
 
-use crate::errors::ErrCode;
+use serde::{Deserialize, Serialize};
 
-const KMEM_LIMIT: i64 = 1024 * 1024 * 1024;
-const MEM_LIMIT: i64 = KMEM_LIMIT;
-const MAX_PID: MaxValue = MaxValue::Value(64);
+use crate::cli::Args;
+use crate::devices::{attach_device_filter, device_rules, DeviceRule};
+use crate::errors::{io_errno, ErrCode, SetupError};
+
+const MEM_LIMIT: i64 = 1024 * 1024 * 1024;
+const MEM_LIMIT_MIN: i64 = 6 * 1024 * 1024;
+const CPU_SHARES: u64 = 256;
+const CPU_PERIOD: u64 = 100_000;
+const MAX_PID: i64 = 64;
+const BLKIO_WEIGHT: u16 = 50;
 const NOFILE_RLIMIT: u64 = 64;
 
-pub fn restrict_resources(hostname: &String, pid: Pid) -> Result<(), ErrCode> {
-    log::debug!("Restricting resources for {}", hostname);
+// What the host has to give, the root cpuset first and else what is online
+const HOST_CPUS: [&str; 3] = [
+    "/sys/fs/cgroup/cpuset.cpus.effective",
+    "/sys/fs/cgroup/cpuset/cpuset.effective_cpus",
+    "/sys/devices/system/cpu/online",
+];
+const HOST_MEMS: [&str; 3] = [
+    "/sys/fs/cgroup/cpuset.mems.effective",
+    "/sys/fs/cgroup/cpuset/cpuset.effective_mems",
+    "/sys/devices/system/node/online",
+];
+
+// Processes stuck in the kernel can take a while to reach the freezer
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+const FREEZE_POLL: Duration = Duration::from_millis(10);
+
+// Resource limits that can be set on the container process
+const RLIMITS: [(&str, Resource); 12] = [
+    ("nofile", Resource::NOFILE),
+    ("nproc", Resource::NPROC),
+    ("core", Resource::CORE),
+    ("stack", Resource::STACK),
+    ("as", Resource::AS),
+    ("cpu", Resource::CPU),
+    ("fsize", Resource::FSIZE),
+    ("memlock", Resource::MEMLOCK),
+    ("msgqueue", Resource::MSGQUEUE),
+    ("nice", Resource::NICE),
+    ("rtprio", Resource::RTPRIO),
+    ("sigpending", Resource::SIGPENDING),
+];
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// How the host mounts its cgroups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CgroupMode {
+    // One v1 hierarchy per controller under /sys/fs/cgroup
+    Legacy,
+    // v1 controllers, with an empty v2 hierarchy in /sys/fs/cgroup/unified
+    Hybrid,
+    // A single v2 hierarchy in /sys/fs/cgroup
+    Unified,
+}
+
+impl CgroupMode {
+    pub fn detect() -> CgroupMode {
+        let is_cgroup2 = |path: &str| {
+            statfs(path).is_ok_and(|fs| fs.filesystem_type() == CGROUP2_SUPER_MAGIC)
+        };
+        if is_cgroup2(CGROUP_ROOT) {
+            CgroupMode::Unified
+        } else if is_cgroup2(&format!("{}/unified", CGROUP_ROOT)) {
+            CgroupMode::Hybrid
+        } else {
+            CgroupMode::Legacy
+        }
+    }
+
+    // Resources are only controlled through the v1 controllers in hybrid mode
+    fn hierarchy(self) -> Box<dyn Hierarchy> {
+        match self {
+            CgroupMode::Unified => Box::new(V2::new()),
+            CgroupMode::Legacy | CgroupMode::Hybrid => Box::new(V1::new()),
+        }
+    }
+}
+
+// Amount of bytes with an optional b, k, m or g unit, "512m" or "1g"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub i64);
+
+impl FromStr for ByteSize {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-1" {
+            return Ok(ByteSize(-1));
+        }
+        let lower = s.trim().to_ascii_lowercase();
+        let lower = lower.trim_end_matches("ib").trim_end_matches('b');
+        let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+        let (number, unit) = lower.split_at(split);
+        let unit: i64 = match unit {
+            "" => 1,
+            "k" => 1 << 10,
+            "m" => 1 << 20,
+            "g" => 1 << 30,
+            _ => return Err(ErrCode::InvalidArgument("size")),
+        };
+        match number.parse::<i64>().ok().and_then(|n| n.checked_mul(unit)) {
+            Some(bytes) => Ok(ByteSize(bytes)),
+            None => Err(ErrCode::InvalidArgument("size")),
+        }
+    }
+}
+
+// Throttling of a block device: PATH:RATE, bytes or operations per second
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRate {
+    pub path: PathBuf,
+    pub rate: u64,
+}
+
+impl FromStr for DeviceRate {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, rate) = s
+            .rsplit_once(':')
+            .ok_or(ErrCode::InvalidArgument("device rate"))?;
+        match rate.parse::<ByteSize>() {
+            Ok(ByteSize(rate)) if rate > 0 && !path.is_empty() => Ok(DeviceRate {
+                path: PathBuf::from(path),
+                rate: rate as u64,
+            }),
+            _ => Err(ErrCode::InvalidArgument("device rate")),
+        }
+    }
+}
+
+// NAME=SOFT[:HARD] with the lowercase name of the resource ("nofile"),
+// -1 or "unlimited" for no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ulimit {
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl FromStr for Ulimit {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, limits) = s.split_once('=').ok_or(ErrCode::InvalidArgument("ulimit"))?;
+        let resource = match RLIMITS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, resource)) => *resource,
+            None => return Err(ErrCode::InvalidArgument("ulimit")),
+        };
+        let parse_limit = |l: &str| match l {
+            "-1" | "unlimited" => Ok(INFINITY),
+            _ => l.parse::<u64>().or(Err(ErrCode::InvalidArgument("ulimit"))),
+        };
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+            None => (parse_limit(limits)?, parse_limit(limits)?),
+        };
+        if soft > hard {
+            return Err(ErrCode::InvalidArgument("ulimit"));
+        }
+        Ok(Ulimit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+// Block device throttling resolved to its device number
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceThrottle {
+    pub major: u64,
+    pub minor: u64,
+    pub rate: u64,
+}
+
+// Limits of the container's cgroup and process, defaults when not given
+#[derive(Debug, Clone)]
+pub struct ResourceLimits {
+    pub memory: i64,
+    pub memory_swap: Option<i64>,
+    pub cpu_quota: Option<i64>,
+    pub cpu_shares: u64,
+    pub cpuset_cpus: Option<String>,
+    pub cpuset_mems: Option<String>,
+    pub pids_limit: MaxValue,
+    pub blkio_weight: u16,
+    pub read_bps: Vec<DeviceThrottle>,
+    pub write_bps: Vec<DeviceThrottle>,
+    pub read_iops: Vec<DeviceThrottle>,
+    pub write_iops: Vec<DeviceThrottle>,
+    pub ulimits: Vec<Ulimit>,
+    pub devices: Vec<DeviceRule>,
+}
+
+impl ResourceLimits {
+    pub fn from_args(args: &Args) -> Result<ResourceLimits, ErrCode> {
+        let memory = args.memory.map_or(MEM_LIMIT, |m| m.0);
+        if memory < MEM_LIMIT_MIN {
+            log::error!("The memory limit must be at least {} bytes", MEM_LIMIT_MIN);
+            return Err(ErrCode::InvalidArgument("memory"));
+        }
+        // Like Docker, the limit covers memory and swap together
+        let memory_swap = match args.memory_swap {
+            None | Some(ByteSize(-1)) => None,
+            Some(ByteSize(swap)) if swap >= memory => Some(swap),
+            Some(_) => {
+                log::error!("The memory and swap limit can't be lower than the memory limit");
+                return Err(ErrCode::InvalidArgument("memory-swap"));
+            }
+        };
+
+        let cpu_quota = match args.cpus {
+            None => None,
+            Some(cpus) if cpus > 0.0 && cpus <= host_cpus() as f64 => {
+                Some((cpus * CPU_PERIOD as f64) as i64)
+            }
+            Some(_) => {
+                log::error!("Between 0 and {} CPUs can be given", host_cpus());
+                return Err(ErrCode::InvalidArgument("cpus"));
+            }
+        };
+        let cpu_shares = args.cpu_shares.unwrap_or(CPU_SHARES);
+        if !(2..=262144).contains(&cpu_shares) {
+            return Err(ErrCode::InvalidArgument("cpu-shares"));
+        }
+        for (list, arg, host) in [
+            (&args.cpuset_cpus, "cpuset-cpus", HOST_CPUS),
+            (&args.cpuset_mems, "cpuset-mems", HOST_MEMS),
+        ] {
+            let list = match list {
+                Some(list) => list,
+                None => continue,
+            };
+            let ranges = cpuset_ranges(list).ok_or(ErrCode::InvalidArgument(arg))?;
+            // Without anything to compare with, writing the cpuset will tell
+            if let Some(host) = host_cpuset(&host) {
+                if !within_cpuset(&ranges, &host) {
+                    log::error!("{} is not available on the host", list);
+                    return Err(ErrCode::InvalidArgument(arg));
+                }
+            }
+        }
+
+        let pids_limit = match args.pids_limit {
+            None => MaxValue::Value(MAX_PID),
+            Some(limit) if limit <= 0 => MaxValue::Max,
+            Some(limit) => MaxValue::Value(limit),
+        };
+        let blkio_weight = args.blkio_weight.unwrap_or(BLKIO_WEIGHT);
+        if !(10..=1000).contains(&blkio_weight) {
+            return Err(ErrCode::InvalidArgument("blkio-weight"));
+        }
+
+        let mut ulimits = vec![Ulimit {
+            resource: Resource::NOFILE,
+            soft: NOFILE_RLIMIT,
+            hard: NOFILE_RLIMIT,
+        }];
+        for ulimit in args.ulimits.iter() {
+            ulimits.retain(|u| u.resource != ulimit.resource);
+            ulimits.push(*ulimit);
+        }
+
+        Ok(ResourceLimits {
+            memory,
+            memory_swap,
+            cpu_quota,
+            cpu_shares,
+            cpuset_cpus: args.cpuset_cpus.clone(),
+            cpuset_mems: args.cpuset_mems.clone(),
+            pids_limit,
+            blkio_weight,
+            read_bps: device_throttles(&args.device_read_bps, "device-read-bps")?,
+            write_bps: device_throttles(&args.device_write_bps, "device-write-bps")?,
+            read_iops: device_throttles(&args.device_read_iops, "device-read-iops")?,
+            write_iops: device_throttles(&args.device_write_iops, "device-write-iops")?,
+            ulimits,
+            devices: device_rules(&args.devices)?,
+        })
+    }
+}
+
+fn host_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// Lists like "0-3,6"
+fn cpuset_ranges(list: &str) -> Option<Vec<(u32, u32)>> {
+    list.trim()
+        .split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            match (start.parse::<u32>(), end.parse::<u32>()) {
+                (Ok(start), Ok(end)) if start <= end => Some((start, end)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn host_cpuset(files: &[&str]) -> Option<Vec<(u32, u32)>> {
+    let list = files
+        .iter()
+        .filter_map(|f| read_to_string(f).ok())
+        .find(|l| !l.trim().is_empty())?;
+    cpuset_ranges(&list)
+}
+
+// Stops at the first id missing from the host, a huge range costs nothing
+fn within_cpuset(ranges: &[(u32, u32)], host: &[(u32, u32)]) -> bool {
+    ranges.iter().all(|&(start, end)| {
+        (start..=end).all(|id| host.iter().any(|&(s, e)| (s..=e).contains(&id)))
+    })
+}
+
+fn device_throttles(rates: &[DeviceRate], arg: &'static str) -> Result<Vec<DeviceThrottle>, ErrCode> {
+    let mut throttles = vec![];
+    for rate in rates.iter() {
+        let dev = match stat(&rate.path) {
+            Ok(st) if SFlag::from_bits_truncate(st.st_mode).contains(SFlag::S_IFBLK) => st.st_rdev,
+            _ => {
+                log::error!("{} is not a block device", rate.path.display());
+                return Err(ErrCode::InvalidArgument(arg));
+            }
+        };
+        throttles.push(DeviceThrottle {
+            major: major(dev),
+            minor: minor(dev),
+            rate: rate.rate,
+        });
+    }
+    Ok(throttles)
+}
+
+// An absolute path of safe names, without . or .. components
+pub fn valid_cgroup_parent(parent: &str) -> bool {
+    parent.starts_with('/')
+        && parent.split('/').skip(1).filter(|c| !c.is_empty()).all(|c| {
+            c != "." && c != ".." && c.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+        })
+}
+
+// Path of the container's cgroup in every hierarchy
+pub fn cgroup_path(parent: &str, id: &str) -> String {
+    let parent = parent.trim_end_matches('/');
+    format!("{}/{}", parent, id)
+}
+
+// cgroup v2 has weights from 1 to 10000 instead of shares, same conversion as runc
+fn shares_to_weight(shares: u64) -> u64 {
+    1 + ((shares - 2) * 9999) / 262142
+}
+
+// Creates the cgroup of the container, before its process exists
+pub fn restrict_resources(
+    cgroup: &str,
+    limits: &ResourceLimits,
+    mode: CgroupMode,
+) -> Result<Cgroup, ErrCode> {
+    log::debug!("Restricting resources of cgroup {} ({:?} cgroups)", cgroup, mode);
+
+    let shares = match mode {
+        CgroupMode::Unified => shares_to_weight(limits.cpu_shares),
+        CgroupMode::Legacy | CgroupMode::Hybrid => limits.cpu_shares,
+    };
+    // cgroup v1 limits memory and swap together, v2 the swap alone
+    let memory_swap_limit = limits.memory_swap.map(|swap| match mode {
+        CgroupMode::Unified => swap - limits.memory,
+        CgroupMode::Legacy | CgroupMode::Hybrid => swap,
+    });
+    let resources = Resources {
+        cpu: CpuResources {
+            shares: Some(shares),
+            quota: limits.cpu_quota,
+            period: limits.cpu_quota.map(|_| CPU_PERIOD),
+            ..Default::default()
+        },
+        memory: MemoryResources {
+            kernel_memory_limit: Some(limits.memory),
+            memory_hard_limit: Some(limits.memory),
+            memory_swap_limit,
+            ..Default::default()
+        },
+        pid: PidResources {
+            maximum_number_of_processes: Some(limits.pids_limit),
+        },
+        blkio: BlkIoResources {
+            weight: Some(limits.blkio_weight),
+            throttle_read_bps_device: throttle_resources(&limits.read_bps),
+            throttle_write_bps_device: throttle_resources(&limits.write_bps),
+            throttle_read_iops_device: throttle_resources(&limits.read_iops),
+            throttle_write_iops_device: throttle_resources(&limits.write_iops),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let cg = Cgroup::new(mode.hierarchy(), relative(cgroup));
+    // Cgroup::apply stops at the first failing controller, and doesn't check
+    // the cpuset and devices writes at all: these are done by hand
+    if let Err(e) = cg.apply(&resources) {
+        log::error!("Cannot apply the resource limits: {}", e);
+        return Err(ErrCode::ResourcesError(8));
+    }
+    if limits.cpuset_cpus.is_some() || limits.cpuset_mems.is_some() {
+        let cpuset: &CpuSetController = match cg.controller_of() {
+            Some(cpuset) => cpuset,
+            None => {
+                log::error!("No cpuset controller for cgroup {}", cgroup);
+                return Err(ErrCode::ResourcesError(9));
+            }
+        };
+        if let Some(cpus) = &limits.cpuset_cpus {
+            if let Err(e) = cpuset.set_cpus(cpus) {
+                log::error!("Cannot restrict the container to CPUs {}: {}", cpus, e);
+                return Err(ErrCode::ResourcesError(9));
+            }
+        }
+        if let Some(mems) = &limits.cpuset_mems {
+            if let Err(e) = cpuset.set_mems(mems) {
+                log::error!("Cannot restrict the container to memory nodes {}: {}", mems, e);
+                return Err(ErrCode::ResourcesError(9));
+            }
+        }
+    }
+    if mode != CgroupMode::Unified {
+        set_device_rules(&cg, &limits.devices)?;
+    }
+
+    if mode == CgroupMode::Unified {
+        let fd = match open_cgroup(cgroup, mode) {
+            Some(fd) => fd,
+            None => return Err(ErrCode::ResourcesError(7)),
+        };
+        let res = attach_device_filter(fd, &limits.devices);
+        close(fd).ok();
+        res?;
+    }
+    Ok(cg)
+}
+
+fn throttle_resources(throttles: &[DeviceThrottle]) -> Vec<BlkIoDeviceThrottleResource> {
+    throttles
+        .iter()
+        .map(|t| BlkIoDeviceThrottleResource {
+            major: t.major,
+            minor: t.minor,
+            rate: t.rate,
+        })
+        .collect()
+}
+
+// cgroup v1 device access lists, v2 takes a BPF program instead
+fn set_device_rules(cg: &Cgroup, rules: &[DeviceRule]) -> Result<(), ErrCode> {
+    let devices: &DevicesController = match cg.controller_of() {
+        Some(devices) => devices,
+        None => {
+            log::error!("No devices controller for the container's cgroup");
+            return Err(ErrCode::ResourcesError(10));
+        }
+    };
+    for rule in rules.iter() {
+        let (major, minor) = rule.cgroup_numbers();
+        let (kind, access) = (rule.cgroup_type(), rule.cgroup_access());
+        let res = if rule.allow {
+            devices.allow_device(kind, major, minor, &access)
+        } else {
+            devices.deny_device(kind, major, minor, &access)
+        };
+        if let Err(e) = res {
+            log::error!("Cannot set the device rule {:?}: {}", rule, e);
+            return Err(ErrCode::ResourcesError(10));
+        }
+    }
+    Ok(())
+}
 
-    #[rustfmt::skip]
-    let cgs = CgroupBuilder::new(hostname)
-        .cpu().shares(256).done()
-        .memory().kernel_memory_limit(KMEM_LIMIT).memory_hard_limit(MEM_LIMIT).done()
-        .pid().maximum_number_of_processes(MAX_PID).done()
-        .blkio().weight(50).done()
-        .build(Box::new(V2::new()));
+// Directory of the cgroup, for clone3 to start the child in it. Only a v2
+// cgroup can be given to clone3.
+pub fn open_cgroup(cgroup: &str, mode: CgroupMode) -> Option<RawFd> {
+    if mode != CgroupMode::Unified {
+        return None;
+    }
+    let path = format!("{}{}", CGROUP_ROOT, cgroup);
+    match open(path.as_str(), OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => Some(fd),
+        Err(e) => {
+            log::debug!("Cannot open cgroup {}: {}", path, e);
+            None
+        }
+    }
+}
 
+// For a child cloned outside of its cgroup, it waits for this to be done
+pub fn add_to_cgroup(cgroup: &Cgroup, pid: Pid) -> Result<(), ErrCode> {
     let pid: u64 = pid.as_raw().try_into().unwrap();
-    if let Err(_) = cgs.add_task(CgroupPid::from(pid)) {
+    if let Err(_) = cgroup.add_task(CgroupPid::from(pid)) {
         return Err(ErrCode::ResourcesError(0));
     };
 
-    if let Err(_) = setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT) {
-        return Err(ErrCode::ResourcesError(0));
-    }
+    Ok(())
+}
 
+// Called from the child, the limits are inherited through execve. Raising a
+// hard limit above the one of crabcan isn't possible from the user namespace.
+pub fn set_rlimits(ulimits: &[Ulimit]) -> Result<(), SetupError> {
+    log::debug!("Setting resource limits");
+    for ulimit in ulimits.iter() {
+        if let Err(e) = setrlimit(ulimit.resource, ulimit.soft, ulimit.hard) {
+            log::error!("Cannot set {}: {}", ulimit.resource.as_name(), e);
+            return Err(ErrCode::ResourcesError(1).caused_by(io_errno(&e)));
+        }
+    }
     Ok(())
 }
 
-pub fn clean_cgroups(hostname: &String) -> Result<(), ErrCode> {
+// Removes the cgroup from every hierarchy it was created in
+// The parent cgroup is shared with other containers and stays
+pub fn clean_cgroups(cgroup: &str, mode: CgroupMode) -> Result<(), ErrCode> {
     log::debug!("Cleaning cgroups");
-    match canonicalize(format!("/sys/fs/cgroup/{}/", hostname)) {
-        Ok(d) => {
-            if let Err(_) = remove_dir(d) {
-                return Err(ErrCode::ResourcesError(2));
-            }
+    if let Err(e) = Cgroup::load(mode.hierarchy(), relative(cgroup)).delete() {
+        log::error!("Cannot remove cgroup {}: {}", cgroup, e);
+        return Err(ErrCode::ResourcesError(2));
+    }
+    Ok(())
+}
+
+// Stops or restarts every process of the cgroup, waiting until they all are
+pub fn freeze_cgroup(cgroup: &str, mode: CgroupMode, frozen: bool) -> Result<(), ErrCode> {
+    let cg = Cgroup::load(mode.hierarchy(), relative(cgroup));
+    let freezer: &FreezerController = match cg.controller_of() {
+        Some(f) => f,
+        None => {
+            log::error!("No freezer for cgroup {}", cgroup);
+            return Err(ErrCode::ResourcesError(6));
         }
-        Err(e) => {
-            log::error!("Error canonicalizing path: {}", e);
-            return Err(ErrCode::ResourcesError(3));
+    };
+    let res = if frozen { freezer.freeze() } else { freezer.thaw() };
+    if let Err(e) = res {
+        log::error!("Cannot change the freezer state of cgroup {}: {}", cgroup, e);
+        return Err(ErrCode::ResourcesError(6));
+    }
+
+    let start = Instant::now();
+    while is_frozen(cgroup, mode, freezer) != frozen {
+        if start.elapsed() > FREEZE_TIMEOUT {
+            log::error!("Timeout waiting for the freezer of cgroup {}", cgroup);
+            // Don't leave the container half frozen
+            freezer.thaw().ok();
+            return Err(ErrCode::ResourcesError(6));
         }
+        sleep(FREEZE_POLL);
     }
     Ok(())
 }
+
+// The freezer state of v2 is only what was asked for, whether the processes
+// are stopped yet is in cgroup.events
+fn is_frozen(cgroup: &str, mode: CgroupMode, freezer: &FreezerController) -> bool {
+    match mode {
+        CgroupMode::Unified => {
+            read_to_string(controller_dir(cgroup, mode, "freezer").join("cgroup.events"))
+                .is_ok_and(|events| events.lines().any(|l| l == "frozen 1"))
+        }
+        CgroupMode::Legacy | CgroupMode::Hybrid => {
+            matches!(freezer.state(), Ok(FreezerState::Frozen))
+        }
+    }
+}
+
+// Directory holding the files of a controller for the cgroup, v1 has one
+// hierarchy per controller
+pub fn controller_dir(cgroup: &str, mode: CgroupMode, controller: &str) -> PathBuf {
+    match mode {
+        CgroupMode::Unified => PathBuf::from(CGROUP_ROOT).join(relative(cgroup)),
+        _ => PathBuf::from(CGROUP_ROOT).join(controller).join(relative(cgroup)),
+    }
+}
+
+// cgroups-rs takes paths relative to the root of each hierarchy
+fn relative(cgroup: &str) -> &str {
+    cgroup.trim_start_matches('/')
+}
@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, HashMap};
+
+use libseccomp::{ScmpAction, ScmpNotifReq, ScmpNotifResp, ScmpNotifRespFlags};
+
+use crate::seccomp_profile::SeccompFilter;
+use crate::supervisor::syscall_name;
+use crate::syscalls::SeccompAudit;
+
+const EPERM: i32 = 1;
+
+// Distinct argument lists kept for each denied syscall in the report
+const MAX_SAMPLES: usize = 4;
+
+#[derive(Debug, Clone, Default)]
+struct Violations {
+    count: usize,
+    samples: Vec<(i32, [u64; 6])>,
+}
+
+// Answers the notifications of the deny rules of a filter and keeps track of
+// what the container tried
+#[derive(Debug, Clone)]
+pub struct SeccompAuditor {
+    mode: SeccompAudit,
+    errnos: HashMap<String, i32>,
+    default_errno: Option<i32>,
+    violations: BTreeMap<String, Violations>,
+}
+
+impl SeccompAuditor {
+    // Sends the syscalls the filter fails with an errno to the supervisor,
+    // remembering the errno to answer with. The kernel doesn't tell which
+    // rule matched, so a syscall denied by several rules gets the errno of
+    // the first one.
+    pub fn new(mode: SeccompAudit, filter: &mut SeccompFilter) -> SeccompAuditor {
+        let mut errnos = HashMap::new();
+        for rule in filter.rules.iter_mut() {
+            if let ScmpAction::Errno(errno) = rule.action {
+                for name in rule.names.iter() {
+                    errnos.entry(name.clone()).or_insert(errno);
+                }
+                rule.action = ScmpAction::Notify;
+            }
+        }
+        let default_errno = match filter.default_action {
+            ScmpAction::Errno(errno) => {
+                filter.default_action = ScmpAction::Notify;
+                Some(errno)
+            }
+            _ => None,
+        };
+        SeccompAuditor {
+            mode,
+            errnos,
+            default_errno,
+            violations: BTreeMap::new(),
+        }
+    }
+
+    // Whether the syscall reached the supervisor because of a deny rule
+    pub fn handles(&self, name: &str) -> bool {
+        self.default_errno.is_some() || self.errnos.contains_key(name)
+    }
+
+    pub fn record(&mut self, req: &ScmpNotifReq) -> ScmpNotifResp {
+        let name = syscall_name(req);
+        let errno = match self.errnos.get(&name) {
+            Some(errno) => *errno,
+            None => self.default_errno.unwrap_or(EPERM),
+        };
+        log::debug!(
+            "seccomp: {} denied by the filter from pid {}",
+            name,
+            req.pid
+        );
+
+        let violations = self.violations.entry(name).or_default();
+        violations.count += 1;
+        let sample = (req.pid as i32, req.data.args);
+        if violations.samples.len() < MAX_SAMPLES
+            && !violations.samples.iter().any(|(_, args)| *args == sample.1)
+        {
+            violations.samples.push(sample);
+        }
+
+        match self.mode {
+            SeccompAudit::Report => {
+                ScmpNotifResp::new_error(req.id, -errno, ScmpNotifRespFlags::empty())
+            }
+            SeccompAudit::Complain => {
+                ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::empty())
+            }
+        }
+    }
+
+    pub fn report(&self, container: &str) {
+        if self.violations.is_empty() {
+            log::info!("seccomp: container {} hit no deny rule", container);
+            return;
+        }
+        let verb = match self.mode {
+            SeccompAudit::Report => "denied",
+            SeccompAudit::Complain => "would have denied",
+        };
+        for (name, violations) in self.violations.iter() {
+            log::warn!(
+                "seccomp: container {} {} {} {} time(s)",
+                container,
+                verb,
+                name,
+                violations.count
+            );
+            for (pid, args) in violations.samples.iter() {
+                log::warn!(
+                    "seccomp:     {}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}) from pid {}",
+                    name,
+                    args[0],
+                    args[1],
+                    args[2],
+                    args[3],
+                    args[4],
+                    args[5],
+                    pid
+                );
+            }
+        }
+    }
+}
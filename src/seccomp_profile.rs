@@ -0,0 +1,480 @@
+#[cfg(seccomp_notify)]
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufReader;
+#[cfg(seccomp_notify)]
+use std::io::BufWriter;
+use std::path::Path;
+
+use capctl::caps::Cap;
+use libseccomp::{ScmpAction, ScmpArch, ScmpArgCompare, ScmpCompareOp};
+#[cfg(seccomp_notify)]
+use libseccomp::ScmpNotifReq;
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities::CapabilitySets;
+use crate::errors::ErrCode;
+
+const EPERM: u32 = 1;
+#[cfg(seccomp_notify)]
+const ENOSYS: u32 = 38;
+
+#[cfg(seccomp_notify)]
+// Arguments worth turning into rules when learning a profile, they hold
+// flags or commands rather than pointers and sizes
+const LEARNED_ARGS: [(&str, u32); 6] = [
+    ("socket", 0),
+    ("ioctl", 1),
+    ("prctl", 0),
+    ("personality", 0),
+    ("fcntl", 1),
+    ("arch_prctl", 0),
+];
+#[cfg(seccomp_notify)]
+// Past that many distinct values, the syscall is allowed whatever the argument
+const LEARNED_VALUES_MAX: usize = 16;
+
+// Seccomp profile in the Docker/OCI JSON format
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SeccompProfile {
+    pub default_action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arch_map: Vec<ArchMap>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener_metadata: Option<String>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ArchMap {
+    pub architecture: String,
+    #[serde(default)]
+    pub sub_architectures: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SyscallRule {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,
+    // Single syscall form of older Docker profiles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<SyscallArg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub includes: Option<RuleFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excludes: Option<RuleFilter>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub value_two: u64,
+    pub op: String,
+}
+
+// Conditions under which a Docker rule applies
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RuleFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub caps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arches: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_kernel: Option<String>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+// Filter ready to be loaded, either built-in or translated from a profile
+#[derive(Debug, Clone)]
+pub struct SeccompFilter {
+    pub default_action: ScmpAction,
+    pub architectures: Vec<ScmpArch>,
+    pub log: bool,
+    pub spec_allow: bool,
+    pub rules: Vec<FilterRule>,
+}
+
+impl SeccompFilter {
+    // The filter needs a supervisor answering its notifications
+    pub fn uses_notify(&self) -> bool {
+        self.default_action == ScmpAction::Notify
+            || self.rules.iter().any(|r| r.action == ScmpAction::Notify)
+    }
+
+    // Sends these syscalls to the supervisor, whatever the other rules say
+    #[cfg(seccomp_notify)]
+    pub fn notify(&mut self, names: &[String]) {
+        for rule in self.rules.iter_mut() {
+            rule.names.retain(|n| !names.contains(n));
+        }
+        self.rules.retain(|r| !r.names.is_empty());
+        self.rules.push(FilterRule {
+            names: names.to_vec(),
+            action: ScmpAction::Notify,
+            args: vec![],
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterRule {
+    pub names: Vec<String>,
+    pub action: ScmpAction,
+    pub args: Vec<ScmpArgCompare>,
+}
+
+fn unsupported(what: String) -> ErrCode {
+    log::error!("Unsupported seccomp profile: {}", what);
+    ErrCode::InvalidArgument("seccomp-profile")
+}
+
+impl SeccompProfile {
+    pub fn load(path: &Path) -> Result<SeccompProfile, ErrCode> {
+        log::debug!("Loading seccomp profile {}", path.display());
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot open seccomp profile {}: {}", path.display(), e);
+                return Err(ErrCode::InvalidArgument("seccomp-profile"));
+            }
+        };
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(profile) => Ok(profile),
+            Err(e) => Err(unsupported(e.to_string())),
+        }
+    }
+
+    #[cfg(seccomp_notify)]
+    pub fn save(&self, path: &Path) -> Result<(), ErrCode> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot create seccomp profile {}: {}", path.display(), e);
+                return Err(ErrCode::SyscallsError(7));
+            }
+        };
+        if let Err(e) = serde_json::to_writer_pretty(BufWriter::new(file), self) {
+            log::error!("Cannot write seccomp profile {}: {}", path.display(), e);
+            return Err(ErrCode::SyscallsError(7));
+        }
+        Ok(())
+    }
+
+    // Rules whose includes / excludes don't match the container are left out
+    pub fn to_filter(&self, caps: &CapabilitySets) -> Result<SeccompFilter, ErrCode> {
+        if self.listener_path.is_some() || self.listener_metadata.is_some() {
+            return Err(unsupported("listenerPath".to_string()));
+        }
+
+        let mut filter = SeccompFilter {
+            default_action: parse_action(&self.default_action, self.default_errno_ret)?,
+            architectures: vec![],
+            log: false,
+            spec_allow: false,
+            rules: vec![],
+        };
+
+        for flag in self.flags.iter() {
+            match flag.as_str() {
+                "SECCOMP_FILTER_FLAG_LOG" => filter.log = true,
+                "SECCOMP_FILTER_FLAG_SPEC_ALLOW" => filter.spec_allow = true,
+                // The container process is single threaded when the filter is loaded
+                "SECCOMP_FILTER_FLAG_TSYNC" => (),
+                _ => return Err(unsupported(format!("flag {}", flag))),
+            }
+        }
+
+        let native = ScmpArch::native();
+        for arch in self.architectures.iter() {
+            filter.architectures.push(parse_arch(arch)?);
+        }
+        for map in self.arch_map.iter() {
+            if parse_arch(&map.architecture)? != native {
+                continue;
+            }
+            filter.architectures.push(native);
+            for arch in map.sub_architectures.iter() {
+                filter.architectures.push(parse_arch(arch)?);
+            }
+        }
+
+        let kernel = kernel_version(nix::sys::utsname::uname().release());
+        for rule in self.syscalls.iter() {
+            if !rule_applies(rule, caps, kernel)? {
+                log::debug!("Skipping seccomp rule for {:?}", rule.names);
+                continue;
+            }
+            filter.rules.extend(translate_rule(rule)?);
+        }
+        Ok(filter)
+    }
+}
+
+fn parse_action(action: &str, errno: Option<u32>) -> Result<ScmpAction, ErrCode> {
+    let errno = errno.unwrap_or(EPERM) as i32;
+    match ScmpAction::from_str(action, Some(errno)) {
+        Ok(action) => Ok(action),
+        Err(_) => Err(unsupported(format!("action {}", action))),
+    }
+}
+
+fn parse_arch(arch: &str) -> Result<ScmpArch, ErrCode> {
+    match arch.parse::<ScmpArch>() {
+        Ok(arch) => Ok(arch),
+        Err(_) => Err(unsupported(format!("architecture {}", arch))),
+    }
+}
+
+#[cfg(seccomp_notify)]
+fn arch_name(arch: ScmpArch) -> Option<&'static str> {
+    match arch {
+        ScmpArch::X86 => Some("SCMP_ARCH_X86"),
+        ScmpArch::X8664 => Some("SCMP_ARCH_X86_64"),
+        ScmpArch::X32 => Some("SCMP_ARCH_X32"),
+        ScmpArch::Arm => Some("SCMP_ARCH_ARM"),
+        ScmpArch::Aarch64 => Some("SCMP_ARCH_AARCH64"),
+        ScmpArch::Ppc64Le => Some("SCMP_ARCH_PPC64LE"),
+        ScmpArch::S390X => Some("SCMP_ARCH_S390X"),
+        ScmpArch::Riscv64 => Some("SCMP_ARCH_RISCV64"),
+        _ => None,
+    }
+}
+
+// Architecture names used by Docker in includes / excludes
+fn docker_arch(arch: ScmpArch) -> Option<&'static str> {
+    match arch {
+        ScmpArch::X8664 => Some("amd64"),
+        ScmpArch::X86 => Some("386"),
+        ScmpArch::X32 => Some("x32"),
+        ScmpArch::Aarch64 => Some("arm64"),
+        ScmpArch::Arm => Some("arm"),
+        ScmpArch::Ppc64Le => Some("ppc64le"),
+        ScmpArch::S390X => Some("s390x"),
+        ScmpArch::Riscv64 => Some("riscv64"),
+        _ => None,
+    }
+}
+
+// Major and minor of a "5.15.0-generic" like release
+fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+// Same semantics as Docker: every included condition has to match, any
+// excluded one is enough to skip the rule
+fn rule_applies(
+    rule: &SyscallRule,
+    caps: &CapabilitySets,
+    kernel: Option<(u32, u32)>,
+) -> Result<bool, ErrCode> {
+    let arch = docker_arch(ScmpArch::native());
+    let has_cap = |name: &String| match name.parse::<Cap>() {
+        Ok(cap) => Ok(caps.bounding.has(cap)),
+        Err(_) => Err(unsupported(format!("capability {}", name))),
+    };
+    let kernel_at_least = |min: &String| match kernel_version(min) {
+        Some(min) => Ok(kernel.is_some_and(|k| k >= min)),
+        None => Err(unsupported(format!("minKernel {}", min))),
+    };
+
+    if let Some(inc) = &rule.includes {
+        for cap in inc.caps.iter() {
+            if !has_cap(cap)? {
+                return Ok(false);
+            }
+        }
+        if !inc.arches.is_empty() && !inc.arches.iter().any(|a| Some(a.as_str()) == arch) {
+            return Ok(false);
+        }
+        if let Some(min) = &inc.min_kernel {
+            if !kernel_at_least(min)? {
+                return Ok(false);
+            }
+        }
+    }
+    if let Some(exc) = &rule.excludes {
+        for cap in exc.caps.iter() {
+            if has_cap(cap)? {
+                return Ok(false);
+            }
+        }
+        if exc.arches.iter().any(|a| Some(a.as_str()) == arch) {
+            return Ok(false);
+        }
+        if let Some(min) = &exc.min_kernel {
+            if kernel_at_least(min)? {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn translate_rule(rule: &SyscallRule) -> Result<Vec<FilterRule>, ErrCode> {
+    let mut names = rule.names.clone();
+    names.extend(rule.name.clone());
+    if names.is_empty() {
+        return Err(unsupported("rule without syscall names".to_string()));
+    }
+    let action = parse_action(&rule.action, rule.errno_ret)?;
+
+    let mut args = vec![];
+    for arg in rule.args.iter() {
+        if arg.index > 5 {
+            return Err(unsupported(format!("argument index {}", arg.index)));
+        }
+        let op = match arg.op.as_str() {
+            "SCMP_CMP_MASKED_EQ" => ScmpCompareOp::MaskedEqual(arg.value),
+            op => match op.parse::<ScmpCompareOp>() {
+                Ok(op) if arg.value_two == 0 => op,
+                Ok(_) => return Err(unsupported(format!("valueTwo with {}", op))),
+                Err(_) => return Err(unsupported(format!("operator {}", op))),
+            },
+        };
+        let datum = match op {
+            ScmpCompareOp::MaskedEqual(_) => arg.value_two,
+            _ => arg.value,
+        };
+        args.push((arg.index, ScmpArgCompare::new(arg.index, op, datum)));
+    }
+
+    // Comparisons on distinct arguments must all match, several of them on
+    // the same argument are alternatives and get a rule each (like runc)
+    let repeated = args
+        .iter()
+        .enumerate()
+        .any(|(i, (ind, _))| args[..i].iter().any(|(other, _)| other == ind));
+    let rules = if repeated {
+        args.iter()
+            .map(|(_, cmp)| FilterRule {
+                names: names.clone(),
+                action,
+                args: vec![*cmp],
+            })
+            .collect()
+    } else {
+        vec![FilterRule {
+            names,
+            action,
+            args: args.into_iter().map(|(_, cmp)| cmp).collect(),
+        }]
+    };
+    Ok(rules)
+}
+
+// Syscalls seen by the supervisor in learn mode
+#[cfg(seccomp_notify)]
+#[derive(Debug, Default)]
+pub struct SyscallLearner {
+    // Distinct values of the learned argument, None once there are too many
+    syscalls: BTreeMap<String, Option<BTreeSet<u64>>>,
+    arches: BTreeSet<&'static str>,
+}
+
+#[cfg(seccomp_notify)]
+impl SyscallLearner {
+    pub fn record(&mut self, req: &ScmpNotifReq) {
+        let arch = req.data.arch;
+        let name = match req.data.syscall.get_name_by_arch(arch) {
+            Ok(name) => name,
+            Err(_) => {
+                log::warn!("Unknown syscall {} recorded", i32::from(req.data.syscall));
+                return;
+            }
+        };
+        if let Some(arch) = arch_name(arch) {
+            self.arches.insert(arch);
+        }
+
+        let learned_arg = LEARNED_ARGS.iter().find(|(n, _)| *n == name);
+        let entry = self
+            .syscalls
+            .entry(name)
+            .or_insert_with(|| learned_arg.map(|_| BTreeSet::new()));
+        if let (Some(values), Some((_, ind))) = (entry.as_mut(), learned_arg) {
+            values.insert(req.data.args[*ind as usize]);
+            if values.len() > LEARNED_VALUES_MAX {
+                *entry = None;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.syscalls.is_empty()
+    }
+
+    // Allowlist of what was recorded, everything else fails with ENOSYS
+    pub fn to_profile(&self) -> SeccompProfile {
+        let mut profile = SeccompProfile {
+            default_action: "SCMP_ACT_ERRNO".to_string(),
+            default_errno_ret: Some(ENOSYS),
+            architectures: self.arches.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let mut names = vec![];
+        for (name, values) in self.syscalls.iter() {
+            let values = match values {
+                Some(values) => values,
+                None => {
+                    names.push(name.clone());
+                    continue;
+                }
+            };
+            let ind = LEARNED_ARGS.iter().find(|(n, _)| n == name).map_or(0, |(_, i)| *i);
+            for value in values.iter() {
+                profile.syscalls.push(SyscallRule {
+                    names: vec![name.clone()],
+                    action: "SCMP_ACT_ALLOW".to_string(),
+                    args: vec![SyscallArg {
+                        index: ind,
+                        value: *value,
+                        value_two: 0,
+                        op: "SCMP_CMP_EQ".to_string(),
+                    }],
+                    ..Default::default()
+                });
+            }
+        }
+        if !names.is_empty() {
+            profile.syscalls.insert(
+                0,
+                SyscallRule {
+                    names,
+                    action: "SCMP_ACT_ALLOW".to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+        profile
+    }
+}
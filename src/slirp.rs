@@ -0,0 +1,809 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fs::read_to_string;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant as StdInstant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{connect, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use nix::unistd::{close, read, write};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, HardwareAddress, IpAddress, IpCidr,
+    IpEndpoint, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+};
+
+use crate::errors::ErrCode;
+use crate::network::{PortForward, Protocol, SLIRP_DNS, SLIRP_GATEWAY, SLIRP_GUEST, SLIRP_MTU};
+
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const SLIRP_PREFIX_LEN: u8 = 24;
+
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_PACKETS: usize = 32;
+const UDP_MAX_DATAGRAM: usize = 65535;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// Keep finished TCP flows around a little so the final RST/ACK goes out
+const TCP_LINGER: Duration = Duration::from_secs(1);
+const MAX_POLL_WAIT: Duration = Duration::from_millis(100);
+
+// Past these, new flows are refused: a TCP SYN gets a RST, a datagram is
+// dropped, a connection to a published port is closed
+const MAX_TCP_FLOWS: usize = 256;
+const MAX_UDP_FLOWS: usize = 64;
+// Host sockets of one UDP flow, guest sockets of one published UDP port
+const MAX_UDP_PEERS: usize = 64;
+
+const EPHEMERAL_PORT_FIRST: u16 = 49152;
+
+// Userspace TCP/IP stack serving the tap device of the container.
+// Outbound TCP and UDP flows are NATed through host sockets, 10.0.2.3:53 maps
+// to the host's resolver and, only if allowed, 10.0.2.2 to the host loopback.
+pub struct Slirp {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Slirp {
+    pub fn spawn(
+        tap: RawFd,
+        forwards: &[PortForward],
+        host_loopback: bool,
+    ) -> Result<Slirp, ErrCode> {
+        if fcntl(tap, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).is_err() {
+            close(tap).ok();
+            return Err(ErrCode::NetworkError(9));
+        }
+
+        let stack = match Stack::new(tap, forwards, host_loopback) {
+            Ok(s) => s,
+            Err(e) => {
+                close(tap).ok();
+                return Err(e);
+            }
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        match thread::Builder::new()
+            .name("slirp".to_string())
+            .spawn(move || stack.run(&flag))
+        {
+            Ok(handle) => Ok(Slirp {
+                stop,
+                thread: Some(handle),
+            }),
+            Err(e) => {
+                log::error!("Cannot start user-mode network thread: {}", e);
+                Err(ErrCode::NetworkError(8))
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.thread.take() {
+            if handle.join().is_err() {
+                log::error!("User-mode network thread panicked");
+            }
+        }
+    }
+}
+
+struct TapDevice {
+    fd: RawFd,
+    rx_queue: VecDeque<Vec<u8>>,
+}
+
+struct TapRxToken(Vec<u8>);
+
+struct TapTxToken {
+    fd: RawFd,
+}
+
+impl RxToken for TapRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl TxToken for TapTxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let res = f(&mut frame);
+        // A dropped frame is recovered by the upper layers, like on a real link
+        if let Err(e) = write(self.fd, &frame) {
+            log::debug!("Dropping frame to tap device: {}", e);
+        }
+        res
+    }
+}
+
+impl Device for TapDevice {
+    type RxToken<'a> = TapRxToken;
+    type TxToken<'a> = TapTxToken;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(TapRxToken, TapTxToken)> {
+        self.rx_queue
+            .pop_front()
+            .map(|frame| (TapRxToken(frame), TapTxToken { fd: self.fd }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TapTxToken> {
+        Some(TapTxToken { fd: self.fd })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = SLIRP_MTU + EthernetFrame::<&[u8]>::header_len();
+        caps
+    }
+}
+
+enum HostConn {
+    // Non-blocking connect in progress
+    Connecting(TcpStream),
+    Connected(TcpStream),
+    Closed,
+}
+
+struct TcpRelay {
+    handle: SocketHandle,
+    host: HostConn,
+    to_host: Vec<u8>,
+    host_eof: bool,
+    guest_eof: bool,
+    want_read: bool,
+    created: StdInstant,
+    finished: Option<StdInstant>,
+}
+
+impl TcpRelay {
+    fn new(handle: SocketHandle, host: HostConn) -> TcpRelay {
+        TcpRelay {
+            handle,
+            host,
+            to_host: vec![],
+            host_eof: false,
+            guest_eof: false,
+            want_read: false,
+            created: StdInstant::now(),
+            finished: None,
+        }
+    }
+
+    fn finish(&mut self, socket: &mut tcp::Socket, abort: bool) {
+        if abort {
+            socket.abort();
+        }
+        self.host = HostConn::Closed;
+        self.want_read = false;
+        self.finished = Some(StdInstant::now());
+    }
+
+    // Moves data between the guest socket and the host stream
+    fn pump(&mut self, socket: &mut tcp::Socket, buf: &mut [u8]) {
+        if self.finished.is_some() {
+            return;
+        }
+
+        if let HostConn::Connecting(stream) = &self.host {
+            // Connected once it has a peer, failed if an error is pending
+            let res = match stream.take_error() {
+                Ok(None) => stream.peer_addr().map(|_| ()),
+                Ok(Some(e)) | Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    let host = std::mem::replace(&mut self.host, HostConn::Closed);
+                    if let HostConn::Connecting(stream) = host {
+                        self.host = HostConn::Connected(stream);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotConnected => {
+                    if socket.state() == tcp::State::Listen && self.created.elapsed() > CONNECT_TIMEOUT {
+                        self.finish(socket, true);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    log::debug!("Host connection for {:?} failed: {}", socket.local_endpoint(), e);
+                    return self.finish(socket, true);
+                }
+            }
+        }
+
+        let stream = match &mut self.host {
+            HostConn::Connected(stream) => stream,
+            _ => return,
+        };
+
+        // Guest -> host
+        while self.to_host.len() < TCP_BUFFER_SIZE && socket.can_recv() {
+            match socket.recv_slice(buf) {
+                Ok(n) if n > 0 => self.to_host.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        while !self.to_host.is_empty() {
+            match stream.write(&self.to_host) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.to_host.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return self.finish(socket, true),
+            }
+        }
+
+        let guest_closed = matches!(
+            socket.state(),
+            tcp::State::CloseWait
+                | tcp::State::LastAck
+                | tcp::State::Closing
+                | tcp::State::TimeWait
+                | tcp::State::Closed
+        );
+        if guest_closed && !self.guest_eof && self.to_host.is_empty() {
+            stream.shutdown(Shutdown::Write).ok();
+            self.guest_eof = true;
+        }
+
+        // Host -> guest
+        let space = socket.send_capacity() - socket.send_queue();
+        self.want_read = !self.host_eof && socket.may_send() && space > 0;
+        if self.want_read {
+            let len = space.min(buf.len());
+            match stream.read(&mut buf[..len]) {
+                Ok(0) => {
+                    self.host_eof = true;
+                    self.want_read = false;
+                    socket.close();
+                }
+                Ok(n) => {
+                    socket.send_slice(&buf[..n]).ok();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(_) => return self.finish(socket, true),
+            }
+        }
+
+        if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+            self.finish(socket, false);
+        }
+    }
+}
+
+// Outbound UDP towards one destination, one host socket per guest endpoint
+struct UdpNat {
+    handle: SocketHandle,
+    target: SocketAddr,
+    peers: HashMap<IpEndpoint, (UdpSocket, StdInstant)>,
+}
+
+// Published UDP port, one guest-side socket per host peer
+struct UdpForward {
+    socket: UdpSocket,
+    container_port: u16,
+    peers: HashMap<SocketAddr, (SocketHandle, StdInstant)>,
+}
+
+struct Stack {
+    device: TapDevice,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    upstream_dns: Ipv4Addr,
+    // Whether the gateway leads to the services listening on the host loopback
+    host_loopback: bool,
+    // Keyed by (guest endpoint, remote endpoint as seen by the guest)
+    tcp_flows: HashMap<(IpEndpoint, IpEndpoint), TcpRelay>,
+    // Keyed by the destination endpoint as seen by the guest
+    udp_flows: HashMap<IpEndpoint, UdpNat>,
+    tcp_forwards: Vec<(TcpListener, u16)>,
+    udp_forwards: Vec<UdpForward>,
+    next_port: u16,
+    buf: Vec<u8>,
+}
+
+impl Stack {
+    fn new(tap: RawFd, forwards: &[PortForward], host_loopback: bool) -> Result<Stack, ErrCode> {
+        let mut device = TapDevice {
+            fd: tap,
+            rx_queue: VecDeque::new(),
+        };
+
+        let mut config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        config.random_seed = rand::random();
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for addr in [SLIRP_GATEWAY, SLIRP_DNS] {
+                addrs
+                    .push(IpCidr::new(IpAddress::Ipv4(addr), SLIRP_PREFIX_LEN))
+                    .ok();
+            }
+        });
+        // Routing everything through our own address lets us accept
+        // packets for any destination (AnyIP)
+        if iface.routes_mut().add_default_ipv4_route(SLIRP_GATEWAY).is_err() {
+            return Err(ErrCode::NetworkError(10));
+        }
+        iface.set_any_ip(true);
+
+        let mut tcp_forwards = vec![];
+        let mut udp_forwards = vec![];
+        for fwd in forwards.iter() {
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, fwd.host_port));
+            let res = match fwd.protocol {
+                Protocol::Tcp => TcpListener::bind(addr).and_then(|l| {
+                    l.set_nonblocking(true)?;
+                    tcp_forwards.push((l, fwd.container_port));
+                    Ok(())
+                }),
+                Protocol::Udp => UdpSocket::bind(addr).and_then(|s| {
+                    s.set_nonblocking(true)?;
+                    udp_forwards.push(UdpForward {
+                        socket: s,
+                        container_port: fwd.container_port,
+                        peers: HashMap::new(),
+                    });
+                    Ok(())
+                }),
+            };
+            if let Err(e) = res {
+                log::error!("Cannot publish port {}: {}", fwd, e);
+                return Err(ErrCode::NetworkError(7));
+            }
+            log::debug!("Publishing port {}", fwd);
+        }
+
+        let upstream_dns = host_nameserver();
+        log::debug!("Forwarding DNS requests to {}", upstream_dns);
+
+        Ok(Stack {
+            device,
+            iface,
+            sockets: SocketSet::new(vec![]),
+            upstream_dns,
+            host_loopback,
+            tcp_flows: HashMap::new(),
+            udp_flows: HashMap::new(),
+            tcp_forwards,
+            udp_forwards,
+            next_port: EPHEMERAL_PORT_FIRST,
+            buf: vec![0; UDP_MAX_DATAGRAM],
+        })
+    }
+
+    fn run(mut self, stop: &AtomicBool) {
+        log::debug!("User-mode network started");
+        while !stop.load(Ordering::Relaxed) {
+            if let Err(e) = self.receive_frames() {
+                log::debug!("Tap device closed: {}", e);
+                break;
+            }
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            self.accept_forwards();
+            self.pump_tcp();
+            self.pump_udp();
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            self.wait();
+        }
+        close(self.device.fd).ok();
+        log::debug!("User-mode network stopped");
+    }
+
+    fn receive_frames(&mut self) -> Result<(), Errno> {
+        let mut frame = vec![0; SLIRP_MTU + EthernetFrame::<&[u8]>::header_len()];
+        loop {
+            match read(self.device.fd, &mut frame) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    self.inspect(&frame[..n]);
+                    self.device.rx_queue.push_back(frame[..n].to_vec());
+                }
+                Err(Errno::EAGAIN) => return Ok(()),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sets up NAT sockets for new outbound flows before smoltcp sees the packet
+    fn inspect(&mut self, frame: &[u8]) {
+        let eth = match EthernetFrame::new_checked(frame) {
+            Ok(eth) if eth.ethertype() == EthernetProtocol::Ipv4 => eth,
+            _ => return,
+        };
+        let ip = match Ipv4Packet::new_checked(eth.payload()) {
+            Ok(ip) => ip,
+            Err(_) => return,
+        };
+        let (src, dst) = (IpAddress::Ipv4(ip.src_addr()), IpAddress::Ipv4(ip.dst_addr()));
+        match ip.next_header() {
+            IpProtocol::Tcp => {
+                if let Ok(tcp) = TcpPacket::new_checked(ip.payload()) {
+                    if tcp.syn() && !tcp.ack() {
+                        self.open_tcp(
+                            IpEndpoint::new(src, tcp.src_port()),
+                            IpEndpoint::new(dst, tcp.dst_port()),
+                        );
+                    }
+                }
+            }
+            IpProtocol::Udp => {
+                if let Ok(udp) = UdpPacket::new_checked(ip.payload()) {
+                    self.open_udp(IpEndpoint::new(dst, udp.dst_port()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Where a destination seen by the guest lives on the host network
+    fn host_target(&self, dst: IpEndpoint) -> Option<SocketAddr> {
+        let IpAddress::Ipv4(addr) = dst.addr;
+        let target = if addr == SLIRP_GATEWAY {
+            if !self.host_loopback {
+                return None;
+            }
+            Ipv4Addr::LOCALHOST
+        } else if addr == SLIRP_DNS && dst.port == 53 {
+            self.upstream_dns
+        } else if is_slirp_subnet(addr)
+            || addr.is_broadcast()
+            || addr.is_multicast()
+            || addr.is_unspecified()
+        {
+            return None;
+        } else {
+            addr
+        };
+        Some(SocketAddr::V4(SocketAddrV4::new(target, dst.port)))
+    }
+
+    fn open_tcp(&mut self, guest: IpEndpoint, remote: IpEndpoint) {
+        if self.tcp_flows.contains_key(&(guest, remote)) {
+            return;
+        }
+        let target = match self.host_target(remote) {
+            Some(target) => target,
+            None => return,
+        };
+        if self.tcp_flows.len() >= MAX_TCP_FLOWS {
+            log::debug!("Refusing TCP flow {} -> {}: too many flows", guest, remote);
+            return;
+        }
+
+        let stream = match connect_nonblocking(target) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::debug!("Cannot connect to {}: {}", target, e);
+                return;
+            }
+        };
+        let mut socket = new_tcp_socket();
+        if socket.listen(remote).is_err() {
+            return;
+        }
+        let handle = self.sockets.add(socket);
+
+        log::debug!("New TCP flow {} -> {} (host {})", guest, remote, target);
+        self.tcp_flows.insert(
+            (guest, remote),
+            TcpRelay::new(handle, HostConn::Connecting(stream)),
+        );
+    }
+
+    fn open_udp(&mut self, dst: IpEndpoint) {
+        if self.udp_flows.contains_key(&dst) || self.is_forward_endpoint(dst) {
+            return;
+        }
+        let target = match self.host_target(dst) {
+            Some(target) => target,
+            None => return,
+        };
+        if self.udp_flows.len() >= MAX_UDP_FLOWS {
+            log::debug!("Refusing UDP flow to {}: too many flows", dst);
+            return;
+        }
+
+        let mut socket = new_udp_socket();
+        if socket.bind(dst).is_err() {
+            return;
+        }
+        let handle = self.sockets.add(socket);
+
+        log::debug!("New UDP flow to {} (host {})", dst, target);
+        self.udp_flows.insert(
+            dst,
+            UdpNat {
+                handle,
+                target,
+                peers: HashMap::new(),
+            },
+        );
+    }
+
+    fn is_forward_endpoint(&self, ep: IpEndpoint) -> bool {
+        self.udp_forwards.iter().any(|fwd| {
+            fwd.peers
+                .values()
+                .any(|(handle, _)| self.sockets.get::<udp::Socket>(*handle).endpoint() == ep.into())
+        })
+    }
+
+    fn accept_forwards(&mut self) {
+        let mut accepted = vec![];
+        for (listener, container_port) in self.tcp_forwards.iter() {
+            while let Ok((stream, peer)) = listener.accept() {
+                accepted.push((stream, peer, *container_port));
+            }
+        }
+
+        for (stream, peer, container_port) in accepted {
+            if self.tcp_flows.len() >= MAX_TCP_FLOWS {
+                log::debug!("Refusing connection from {}: too many flows", peer);
+                continue;
+            }
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let guest = IpEndpoint::new(IpAddress::Ipv4(SLIRP_GUEST), container_port);
+            let local = IpEndpoint::new(IpAddress::Ipv4(SLIRP_GATEWAY), ephemeral_port(&mut self.next_port));
+
+            let mut socket = new_tcp_socket();
+            if let Err(e) = socket.connect(self.iface.context(), guest, local) {
+                log::debug!("Cannot forward connection from {}: {}", peer, e);
+                continue;
+            }
+            let handle = self.sockets.add(socket);
+            log::debug!("Forwarding TCP connection {} -> {}", peer, guest);
+            self.tcp_flows
+                .insert((guest, local), TcpRelay::new(handle, HostConn::Connected(stream)));
+        }
+    }
+
+    fn pump_tcp(&mut self) {
+        let mut done = vec![];
+        for (key, relay) in self.tcp_flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(relay.handle);
+            relay.pump(socket, &mut self.buf);
+            if let Some(finished) = relay.finished {
+                if finished.elapsed() > TCP_LINGER {
+                    done.push(*key);
+                }
+            }
+        }
+        for key in done {
+            if let Some(relay) = self.tcp_flows.remove(&key) {
+                self.sockets.remove(relay.handle);
+            }
+        }
+    }
+
+    fn pump_udp(&mut self) {
+        let now = StdInstant::now();
+        let buf = &mut self.buf;
+
+        for nat in self.udp_flows.values_mut() {
+            let socket = self.sockets.get_mut::<udp::Socket>(nat.handle);
+            // Guest -> host
+            while let Ok((n, meta)) = socket.recv_slice(buf) {
+                let full = nat.peers.len() >= MAX_UDP_PEERS;
+                let peer = match nat.peers.entry(meta.endpoint) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(_) if full => continue,
+                    Entry::Vacant(e) => match host_udp_socket(nat.target) {
+                        Ok(s) => e.insert((s, now)),
+                        Err(err) => {
+                            log::debug!("Cannot open host socket to {}: {}", nat.target, err);
+                            continue;
+                        }
+                    },
+                };
+                peer.1 = now;
+                peer.0.send(&buf[..n]).ok();
+            }
+            // Host -> guest
+            for (guest, (host, last)) in nat.peers.iter_mut() {
+                while socket.can_send() {
+                    match host.recv(buf) {
+                        Ok(n) => {
+                            socket.send_slice(&buf[..n], *guest).ok();
+                            *last = now;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+            nat.peers
+                .retain(|_, (_, last)| now.duration_since(*last) < UDP_IDLE_TIMEOUT);
+        }
+
+        let sockets = &mut self.sockets;
+        self.udp_flows.retain(|_, nat| {
+            if nat.peers.is_empty() && !sockets.get::<udp::Socket>(nat.handle).can_recv() {
+                sockets.remove(nat.handle);
+                return false;
+            }
+            true
+        });
+
+        for fwd in self.udp_forwards.iter_mut() {
+            // Host -> guest
+            while let Ok((n, peer)) = fwd.socket.recv_from(buf) {
+                let full = fwd.peers.len() >= MAX_UDP_PEERS;
+                let handle = match fwd.peers.get_mut(&peer) {
+                    Some((handle, last)) => {
+                        *last = now;
+                        *handle
+                    }
+                    None if full => continue,
+                    None => {
+                        let port = ephemeral_port(&mut self.next_port);
+                        let mut socket = new_udp_socket();
+                        if socket
+                            .bind(IpEndpoint::new(IpAddress::Ipv4(SLIRP_GATEWAY), port))
+                            .is_err()
+                        {
+                            continue;
+                        }
+                        let handle = sockets.add(socket);
+                        fwd.peers.insert(peer, (handle, now));
+                        handle
+                    }
+                };
+                let guest = IpEndpoint::new(IpAddress::Ipv4(SLIRP_GUEST), fwd.container_port);
+                sockets
+                    .get_mut::<udp::Socket>(handle)
+                    .send_slice(&buf[..n], guest)
+                    .ok();
+            }
+            // Guest -> host
+            for (peer, (handle, last)) in fwd.peers.iter_mut() {
+                let socket = sockets.get_mut::<udp::Socket>(*handle);
+                while let Ok((n, _)) = socket.recv_slice(buf) {
+                    fwd.socket.send_to(&buf[..n], peer).ok();
+                    *last = now;
+                }
+            }
+            fwd.peers.retain(|_, (handle, last)| {
+                if now.duration_since(*last) < UDP_IDLE_TIMEOUT {
+                    return true;
+                }
+                sockets.remove(*handle);
+                false
+            });
+        }
+    }
+
+    // Sleeps until the tap device or a host socket has something for us
+    fn wait(&mut self) {
+        let timeout = self
+            .iface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map(|d| Duration::from_micros(d.total_micros()))
+            .unwrap_or(MAX_POLL_WAIT)
+            .min(MAX_POLL_WAIT);
+
+        let mut fds = vec![PollFd::new(self.device.fd, PollFlags::POLLIN)];
+        for relay in self.tcp_flows.values() {
+            match &relay.host {
+                HostConn::Connected(stream) => {
+                    let mut flags = PollFlags::empty();
+                    if relay.want_read {
+                        flags |= PollFlags::POLLIN;
+                    }
+                    if !relay.to_host.is_empty() {
+                        flags |= PollFlags::POLLOUT;
+                    }
+                    fds.push(PollFd::new(stream.as_raw_fd(), flags));
+                }
+                HostConn::Connecting(stream) => {
+                    fds.push(PollFd::new(stream.as_raw_fd(), PollFlags::POLLOUT))
+                }
+                HostConn::Closed => (),
+            }
+        }
+        for (listener, _) in self.tcp_forwards.iter() {
+            fds.push(PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN));
+        }
+        for nat in self.udp_flows.values() {
+            for (host, _) in nat.peers.values() {
+                fds.push(PollFd::new(host.as_raw_fd(), PollFlags::POLLIN));
+            }
+        }
+        for fwd in self.udp_forwards.iter() {
+            fds.push(PollFd::new(fwd.socket.as_raw_fd(), PollFlags::POLLIN));
+        }
+
+        poll(&mut fds, timeout.as_millis() as i32).ok();
+    }
+}
+
+fn new_tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    )
+}
+
+fn new_udp_socket() -> udp::Socket<'static> {
+    udp::Socket::new(
+        udp::PacketBuffer::new(
+            vec![udp::PacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+            vec![0; UDP_MAX_DATAGRAM],
+        ),
+        udp::PacketBuffer::new(
+            vec![udp::PacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+            vec![0; UDP_MAX_DATAGRAM],
+        ),
+    )
+}
+
+// Starts connecting without waiting for the handshake, the stream is
+// writable once it is done
+fn connect_nonblocking(target: SocketAddr) -> io::Result<TcpStream> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // Owns fd from here, closing it on error
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    match connect(fd, &SockAddr::new_inet(InetAddr::from_std(&target))) {
+        Ok(()) | Err(Errno::EINPROGRESS) => Ok(stream),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn host_udp_socket(target: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// Local port of the gateway for a connection or datagram from the host,
+// taken from a field of the stack so it can be borrowed on its own
+fn ephemeral_port(next: &mut u16) -> u16 {
+    let port = *next;
+    *next = next.checked_add(1).unwrap_or(EPHEMERAL_PORT_FIRST);
+    port
+}
+
+fn is_slirp_subnet(addr: Ipv4Addr) -> bool {
+    addr.octets()[..3] == SLIRP_GATEWAY.octets()[..3]
+}
+
+// First IPv4 nameserver of the host, falling back to the host loopback
+fn host_nameserver() -> Ipv4Addr {
+    read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<Ipv4Addr>().ok())
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
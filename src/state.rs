@@ -0,0 +1,197 @@
+use std::fs::{create_dir_all, remove_dir_all, remove_file, rename, write, File};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::fcntl::{flock, open, FlockArg, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ErrCode;
+use crate::events::CgroupEvents;
+use crate::resources::CgroupMode;
+
+const STATE_DIR: &str = "/run/crabcan";
+const STATE_FILE: &str = "state.json";
+const STATE_TMP_FILE: &str = "state.json.tmp";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    #[default]
+    Running,
+    Paused,
+}
+
+// What other crabcan commands need to know about a running container
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerState {
+    pub id: String,
+    pub pid: Option<i32>,
+    pub hostname: String,
+    pub cgroup_path: String,
+    pub cgroup_mode: CgroupMode,
+    pub created: u64,
+    #[serde(default)]
+    pub status: ContainerStatus,
+    // When the container got its current status
+    #[serde(default)]
+    pub status_since: u64,
+    #[serde(default)]
+    pub events: CgroupEvents,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Unique name of a container, whatever its hostname
+pub fn generate_container_id() -> String {
+    let bytes: [u8; 8] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Ids are only made of what generate_container_id outputs, so they can't
+// point outside of the state directory
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Writers of a state take the lock of its directory, it goes away with the fd
+fn lock_dir(dir: &Path) -> nix::Result<RawFd> {
+    let fd = open(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?;
+    if let Err(e) = flock(fd, FlockArg::LockExclusive) {
+        close(fd).ok();
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+impl ContainerState {
+    pub fn new(id: String, hostname: String, cgroup_path: String, cgroup_mode: CgroupMode) -> ContainerState {
+        let created = now();
+        ContainerState {
+            id,
+            pid: None,
+            hostname,
+            cgroup_path,
+            cgroup_mode,
+            created,
+            status: ContainerStatus::Running,
+            status_since: created,
+            events: CgroupEvents::default(),
+        }
+    }
+
+    pub fn set_status(&mut self, status: ContainerStatus) {
+        log::debug!("Container {}: {:?} -> {:?}", self.id, self.status, status);
+        self.status = status;
+        self.status_since = now();
+    }
+
+    // State of a running container, from the id printed when it was created
+    pub fn load(id: &str) -> Result<ContainerState, ErrCode> {
+        if !valid_id(id) {
+            return Err(ErrCode::InvalidArgument("container id"));
+        }
+        let path = ContainerState::dir(id).join(STATE_FILE);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("No container {}: {}", id, e);
+                return Err(ErrCode::ContainerError(2));
+            }
+        };
+        serde_json::from_reader(file).map_err(|e| {
+            log::error!("Cannot read state of container {}: {}", id, e);
+            ErrCode::ContainerError(2)
+        })
+    }
+
+    fn dir(id: &str) -> PathBuf {
+        PathBuf::from(STATE_DIR).join(id)
+    }
+
+    pub fn save(&self) -> Result<(), ErrCode> {
+        let dir = ContainerState::dir(&self.id);
+        if let Err(e) = create_dir_all(&dir) {
+            log::error!("Cannot write state of container {}: {}", self.id, e);
+            return Err(ErrCode::ContainerError(2));
+        }
+        let lock = match lock_dir(&dir) {
+            Ok(fd) => fd,
+            Err(e) => {
+                log::error!("Cannot write state of container {}: {}", self.id, e);
+                return Err(ErrCode::ContainerError(2));
+            }
+        };
+        let res = self.write(&dir);
+        close(lock).ok();
+        res
+    }
+
+    // Changes the state as it is saved right now, not as some earlier load
+    // saw it, so that concurrent commands don't undo each other's changes
+    pub fn update<F>(id: &str, change: F) -> Result<ContainerState, ErrCode>
+    where
+        F: FnOnce(&mut ContainerState) -> Result<(), ErrCode>,
+    {
+        if !valid_id(id) {
+            return Err(ErrCode::InvalidArgument("container id"));
+        }
+        let dir = ContainerState::dir(id);
+        let lock = match lock_dir(&dir) {
+            Ok(fd) => fd,
+            Err(e) => {
+                log::error!("No container {}: {}", id, e);
+                return Err(ErrCode::ContainerError(2));
+            }
+        };
+        let res = ContainerState::load(id).and_then(|mut state| {
+            change(&mut state)?;
+            state.write(&dir)?;
+            Ok(state)
+        });
+        close(lock).ok();
+        res
+    }
+
+    // Renamed over the old file once complete, readers never get half of it
+    fn write(&self, dir: &Path) -> Result<(), ErrCode> {
+        let json = match serde_json::to_string_pretty(self) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("Cannot write state of container {}: {}", self.id, e);
+                return Err(ErrCode::ContainerError(2));
+            }
+        };
+        let tmp = dir.join(STATE_TMP_FILE);
+        if let Err(e) = write(&tmp, json).and_then(|_| rename(&tmp, dir.join(STATE_FILE))) {
+            log::error!("Cannot write state of container {}: {}", self.id, e);
+            remove_file(&tmp).ok();
+            return Err(ErrCode::ContainerError(2));
+        }
+        Ok(())
+    }
+
+    pub fn exists(&self) -> bool {
+        ContainerState::dir(&self.id).exists()
+    }
+
+    pub fn remove(&self) -> Result<(), ErrCode> {
+        let dir = ContainerState::dir(&self.id);
+        if !dir.exists() {
+            return Ok(());
+        }
+        if let Err(e) = remove_dir_all(&dir) {
+            log::error!("Cannot remove state of container {}: {}", self.id, e);
+            return Err(ErrCode::ContainerError(2));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, File};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use serde::Serialize;
+
+use crate::cli::StatsArgs;
+use crate::errors::ErrCode;
+use crate::events::CgroupEvents;
+use crate::resources::{controller_dir, CgroupMode};
+use crate::state::ContainerState;
+
+const REFRESH: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStats {
+    pub id: String,
+    pub memory_usage: u64,
+    pub memory_peak: u64,
+    pub memory_stat: BTreeMap<String, u64>,
+    pub cpu_usage_usec: u64,
+    pub cpu_user_usec: u64,
+    pub cpu_system_usec: u64,
+    pub pids: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+    pub io_read_ops: u64,
+    pub io_write_ops: u64,
+    pub networks: BTreeMap<String, NetworkStats>,
+}
+
+fn read_file(path: &Path) -> Result<String, ErrCode> {
+    read_to_string(path).map_err(|e| {
+        log::error!("Cannot read {}: {}", path.display(), e);
+        ErrCode::ResourcesError(3)
+    })
+}
+
+fn read_value(path: &Path) -> Result<u64, ErrCode> {
+    Ok(read_file(path)?.trim().parse().unwrap_or(0))
+}
+
+// For the files older kernels don't have
+fn read_optional(path: &Path) -> u64 {
+    read_to_string(path)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// Files made of "KEY VALUE" lines, like memory.stat or cpu.stat
+fn read_keyed(path: &Path) -> Result<BTreeMap<String, u64>, ErrCode> {
+    Ok(read_file(path)?
+        .lines()
+        .filter_map(|l| {
+            let (key, value) = l.split_once(' ')?;
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect())
+}
+
+fn clock_ticks() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        t if t > 0 => t as u64,
+        _ => 100,
+    }
+}
+
+impl ContainerStats {
+    pub fn collect(state: &ContainerState) -> Result<ContainerStats, ErrCode> {
+        let mut stats = ContainerStats::read_cgroup(&state.cgroup_path, state.cgroup_mode)?;
+        stats.id = state.id.clone();
+
+        // The network namespace is only reachable through a process inside
+        if let Some(pid) = state.pid {
+            let dev = read_file(Path::new(&format!("/proc/{}/net/dev", pid)))?;
+            stats.networks = read_net_dev(&dev);
+        }
+        Ok(stats)
+    }
+
+    pub fn read_cgroup(cgroup: &str, mode: CgroupMode) -> Result<ContainerStats, ErrCode> {
+        let mut stats = ContainerStats::default();
+        let dir = |controller| controller_dir(cgroup, mode, controller);
+
+        if mode == CgroupMode::Unified {
+            stats.memory_usage = read_value(&dir("memory").join("memory.current"))?;
+            stats.memory_peak = read_optional(&dir("memory").join("memory.peak"));
+            let cpu = read_keyed(&dir("cpu").join("cpu.stat"))?;
+            stats.cpu_usage_usec = cpu.get("usage_usec").copied().unwrap_or(0);
+            stats.cpu_user_usec = cpu.get("user_usec").copied().unwrap_or(0);
+            stats.cpu_system_usec = cpu.get("system_usec").copied().unwrap_or(0);
+            stats.read_io_stat(&read_file(&dir("io").join("io.stat"))?);
+        } else {
+            stats.memory_usage = read_value(&dir("memory").join("memory.usage_in_bytes"))?;
+            stats.memory_peak = read_value(&dir("memory").join("memory.max_usage_in_bytes"))?;
+            stats.cpu_usage_usec = read_value(&dir("cpuacct").join("cpuacct.usage"))? / 1000;
+            // cpuacct.stat counts in clock ticks
+            let cpu = read_keyed(&dir("cpuacct").join("cpuacct.stat"))?;
+            let usec = |key| cpu.get(key).copied().unwrap_or(0) * 1_000_000 / clock_ticks();
+            stats.cpu_user_usec = usec("user");
+            stats.cpu_system_usec = usec("system");
+            let blkio = dir("blkio");
+            (stats.io_read_bytes, stats.io_write_bytes) =
+                read_blkio(&read_file(&blkio.join("blkio.throttle.io_service_bytes"))?);
+            (stats.io_read_ops, stats.io_write_ops) =
+                read_blkio(&read_file(&blkio.join("blkio.throttle.io_serviced"))?);
+        }
+        stats.memory_stat = read_keyed(&dir("memory").join("memory.stat"))?;
+        stats.pids = read_value(&dir("pids").join("pids.current"))?;
+        Ok(stats)
+    }
+
+    // One line per device: "MAJ:MIN rbytes=N wbytes=N rios=N wios=N ..."
+    fn read_io_stat(&mut self, content: &str) {
+        for field in content.lines().flat_map(|l| l.split_whitespace().skip(1)) {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value: u64 = value.parse().unwrap_or(0);
+            match key {
+                "rbytes" => self.io_read_bytes += value,
+                "wbytes" => self.io_write_bytes += value,
+                "rios" => self.io_read_ops += value,
+                "wios" => self.io_write_ops += value,
+                _ => {}
+            }
+        }
+    }
+}
+
+// Lines "MAJ:MIN Read N" and "MAJ:MIN Write N" of a blkio file, summed over
+// the devices
+fn read_blkio(content: &str) -> (u64, u64) {
+    let (mut read, mut write) = (0, 0);
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        let value: u64 = fields[2].parse().unwrap_or(0);
+        match fields[1] {
+            "Read" => read += value,
+            "Write" => write += value,
+            _ => {}
+        }
+    }
+    (read, write)
+}
+
+// /proc/net/dev, after two header lines:
+// "IFACE: RXBYTES RXPACKETS ERRS DROP FIFO FRAME COMPRESSED MULTICAST TXBYTES TXPACKETS ..."
+fn read_net_dev(content: &str) -> BTreeMap<String, NetworkStats> {
+    content
+        .lines()
+        .skip(2)
+        .filter_map(|l| {
+            let (iface, counters) = l.split_once(':')?;
+            let counters: Vec<u64> = counters
+                .split_whitespace()
+                .map(|c| c.parse().unwrap_or(0))
+                .collect();
+            if counters.len() < 10 {
+                return None;
+            }
+            Some((
+                iface.trim().to_string(),
+                NetworkStats {
+                    rx_bytes: counters[0],
+                    rx_packets: counters[1],
+                    tx_bytes: counters[8],
+                    tx_packets: counters[9],
+                },
+            ))
+        })
+        .collect()
+}
+
+// What a finished container used, from its cgroup just before it is removed
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitSummary {
+    pub id: String,
+    pub wall_time_ms: u64,
+    pub cpu_user_usec: u64,
+    pub cpu_system_usec: u64,
+    pub memory_peak: u64,
+    pub pids_peak: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>,
+    pub oom_killed: bool,
+}
+
+impl ExitSummary {
+    pub fn new(
+        id: &str,
+        wall_time: Duration,
+        usage: &ContainerStats,
+        events: &CgroupEvents,
+        status: Option<WaitStatus>,
+    ) -> ExitSummary {
+        let (exit_code, signal) = match status {
+            Some(WaitStatus::Exited(_, code)) => (Some(code), None),
+            Some(WaitStatus::Signaled(_, signal, _)) => (None, Some(signal)),
+            _ => (None, None),
+        };
+        ExitSummary {
+            id: id.to_string(),
+            wall_time_ms: wall_time.as_millis() as u64,
+            cpu_user_usec: usage.cpu_user_usec,
+            cpu_system_usec: usage.cpu_system_usec,
+            memory_peak: usage.memory_peak,
+            pids_peak: events.pids_peak,
+            io_read_bytes: usage.io_read_bytes,
+            io_write_bytes: usage.io_write_bytes,
+            exit_code,
+            signal: signal.map(|s| s.as_str().to_string()),
+            // Killed processes other than the init one leave it in charge
+            oom_killed: signal == Some(Signal::SIGKILL) && events.oom_kill > 0,
+        }
+    }
+
+    pub fn log(&self) {
+        let seconds = |usec: u64| usec as f64 / 1_000_000.0;
+        match (self.exit_code, &self.signal) {
+            (Some(code), _) => log::info!(
+                "Container {} exited with code {} after {:.2}s",
+                self.id,
+                code,
+                seconds(self.wall_time_ms * 1000)
+            ),
+            (None, Some(signal)) => log::info!(
+                "Container {} was killed by {} after {:.2}s",
+                self.id,
+                signal,
+                seconds(self.wall_time_ms * 1000)
+            ),
+            (None, None) => (),
+        }
+        log::info!(
+            "Container {}: {:.2}s user, {:.2}s system, {} peak memory, {} pids at most, {} read, {} written",
+            self.id,
+            seconds(self.cpu_user_usec),
+            seconds(self.cpu_system_usec),
+            human_size(self.memory_peak),
+            self.pids_peak,
+            human_size(self.io_read_bytes),
+            human_size(self.io_write_bytes)
+        );
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ErrCode> {
+        let res = File::create(path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer_pretty(f, self).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            log::error!("Cannot write the stats to {}: {}", path.display(), e);
+            return Err(ErrCode::ContainerError(4));
+        }
+        Ok(())
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+fn print_table(stats: &ContainerStats, cpu_percent: f64) {
+    let (rx, tx) = stats
+        .networks
+        .iter()
+        .filter(|(iface, _)| iface.as_str() != "lo")
+        .fold((0, 0), |(rx, tx), (_, n)| {
+            (rx + n.rx_bytes, tx + n.tx_bytes)
+        });
+    // Clear the terminal and go back to the top left corner
+    print!("\x1b[2J\x1b[H");
+    println!(
+        "{:<18}{:>8}{:>12}{:>6}{:>22}{:>22}",
+        "CONTAINER", "CPU %", "MEM USAGE", "PIDS", "BLOCK I/O", "NET I/O"
+    );
+    println!(
+        "{:<18}{:>7.2}%{:>12}{:>6}{:>22}{:>22}",
+        stats.id,
+        cpu_percent,
+        human_size(stats.memory_usage),
+        stats.pids,
+        format!(
+            "{} / {}",
+            human_size(stats.io_read_bytes),
+            human_size(stats.io_write_bytes)
+        ),
+        format!("{} / {}", human_size(rx), human_size(tx)),
+    );
+}
+
+pub fn show(args: StatsArgs) -> Result<(), ErrCode> {
+    let state = ContainerState::load(&args.id)?;
+
+    if args.json {
+        let stats = ContainerStats::collect(&state)?;
+        match serde_json::to_string_pretty(&stats) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                log::error!("Cannot serialize the stats: {}", e);
+                return Err(ErrCode::ResourcesError(3));
+            }
+        }
+        return Ok(());
+    }
+
+    // CPU usage is the CPU time spent between two refreshes
+    let mut previous: Option<(Instant, u64)> = None;
+    while state.exists() {
+        let stats = match ContainerStats::collect(&state) {
+            Ok(stats) => stats,
+            // The container exited between the check and the read
+            Err(_) if !state.exists() => break,
+            Err(e) => return Err(e),
+        };
+        let now = Instant::now();
+        let cpu_percent = match previous {
+            Some((at, usage)) => {
+                let elapsed = now.duration_since(at).as_micros() as f64;
+                stats.cpu_usage_usec.saturating_sub(usage) as f64 * 100.0 / elapsed
+            }
+            None => 0.0,
+        };
+        print_table(&stats, cpu_percent);
+        previous = Some((now, stats.cpu_usage_usec));
+        sleep(REFRESH);
+    }
+    log::info!("Container {} exited", state.id);
+    Ok(())
+}
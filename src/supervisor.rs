@@ -0,0 +1,101 @@
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use libseccomp::{ScmpNotifReq, ScmpNotifResp};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::close;
+
+use crate::errors::ErrCode;
+
+const POLL_TIMEOUT_MS: i32 = 100;
+
+// Answers the seccomp user notifications of the container, from a thread of
+// the parent process
+pub struct Supervisor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn spawn<F>(listener: RawFd, handler: F) -> Result<Supervisor, ErrCode>
+    where
+        F: FnMut(RawFd, &ScmpNotifReq) -> ScmpNotifResp + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        match thread::Builder::new()
+            .name("seccomp".to_string())
+            .spawn(move || supervise(listener, handler, &flag))
+        {
+            Ok(handle) => Ok(Supervisor {
+                stop,
+                thread: Some(handle),
+            }),
+            Err(e) => {
+                log::error!("Cannot start seccomp supervisor thread: {}", e);
+                close(listener).ok();
+                Err(ErrCode::SyscallsError(6))
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.thread.take() {
+            if handle.join().is_err() {
+                log::error!("Seccomp supervisor thread panicked");
+            }
+        }
+    }
+}
+
+// Name of the notified syscall, for the log
+pub fn syscall_name(req: &ScmpNotifReq) -> String {
+    match req.data.syscall.get_name_by_arch(req.data.arch) {
+        Ok(name) => name,
+        Err(_) => format!("syscall {}", i32::from(req.data.syscall)),
+    }
+}
+
+fn supervise<F>(listener: RawFd, mut handler: F, stop: &AtomicBool)
+where
+    F: FnMut(RawFd, &ScmpNotifReq) -> ScmpNotifResp,
+{
+    log::debug!("Seccomp supervisor started");
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(listener, PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_TIMEOUT_MS) {
+            Ok(0) => continue,
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Cannot poll seccomp listener: {}", e);
+                break;
+            }
+        }
+        let revents = fds[0].revents().unwrap_or_else(PollFlags::empty);
+        if !revents.contains(PollFlags::POLLIN) {
+            // Every process of the container using the filter is gone
+            if revents.contains(PollFlags::POLLHUP) {
+                break;
+            }
+            continue;
+        }
+
+        // The syscall may have been interrupted since, receive and respond
+        // fail with ENOENT then
+        let req = match ScmpNotifReq::receive(listener) {
+            Ok(req) => req,
+            Err(e) => {
+                log::debug!("Lost seccomp notification: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handler(listener, &req).respond(listener) {
+            log::debug!("Cannot answer seccomp notification {}: {}", req.id, e);
+        }
+    }
+    close(listener).ok();
+    log::debug!("Seccomp supervisor stopped");
+}
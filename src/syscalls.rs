@@ -1,79 +1,462 @@
-use crate::errors::ErrCode;
-use syscallz::{Action, Cmp, Comparator, Context, Syscall};
+use crate::capabilities::CapabilitySets;
+use crate::errors::{ErrCode, SetupError};
+#[cfg(seccomp_notify)]
+use crate::ipc::{send_fd, FdKind};
+use crate::seccomp_profile::{FilterRule, SeccompFilter};
+use libseccomp::error::{SeccompErrno, SeccompError};
+use libseccomp::{
+    ScmpAction, ScmpArch, ScmpArgCompare, ScmpCompareOp, ScmpFilterContext, ScmpSyscall,
+};
 
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+
+use capctl::caps::Cap;
 use libc::TIOCSTI;
+use nix::errno::Errno;
 use nix::sched::CloneFlags;
 use nix::sys::stat::Mode;
+#[cfg(seccomp_notify)]
+use nix::unistd::close;
+
+const EPERM: i32 = 1;
+const ENOSYS: i32 = 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompMode {
+    // Everything allowed except a few dangerous syscalls
+    Denylist,
+    // Only a curated set of syscalls allowed
+    Allowlist,
+}
 
-const EPERM: u16 = 1;
+impl FromStr for SeccompMode {
+    type Err = ErrCode;
 
-fn refuse_syscall(ctx: &mut Context, sc: &Syscall) -> Result<(), ErrCode> {
-    match ctx.set_action_for_syscall(Action::Errno(EPERM), *sc) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ErrCode::SyscallsError(2)),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "denylist" => Ok(SeccompMode::Denylist),
+            "allowlist" => Ok(SeccompMode::Allowlist),
+            _ => Err(ErrCode::InvalidArgument("seccomp-mode")),
+        }
     }
 }
 
-fn refuse_if_comp(ctx: &mut Context, ind: u32, sc: &Syscall, biteq: u64) -> Result<(), ErrCode> {
-    match ctx.set_rule_for_syscall(
-        Action::Errno(EPERM),
-        *sc,
-        &[Comparator::new(ind, Cmp::MaskedEq, biteq, Some(biteq))],
-    ) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ErrCode::SyscallsError(3)),
+// Mode of --seccomp-audit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompAudit {
+    // Syscalls still fail, each denial is recorded
+    Report,
+    // Denied syscalls are let through and recorded, like SCMP_ACT_LOG
+    Complain,
+}
+
+impl FromStr for SeccompAudit {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(SeccompAudit::Report),
+            "complain" => Ok(SeccompAudit::Complain),
+            _ => Err(ErrCode::InvalidArgument("seccomp-audit")),
+        }
     }
 }
 
-pub fn setsyscalls() -> Result<(), ErrCode> {
-    log::debug!("Filtering unwanted syscalls");
+// Unconditional syscall deny
+const SYSCALLS_REFUSED: [&str; 9] = [
+    "keyctl",
+    "add_key",
+    "request_key",
+    "mbind",
+    "migrate_pages",
+    "move_pages",
+    "set_mempolicy",
+    "userfaultfd",
+    "perf_event_open",
+];
+
+// Syscalls most workloads need, close to the default profile of Docker
+const SYSCALLS_ALLOWED: &[&str] = &[
+    "accept", "accept4", "access", "adjtimex", "alarm", "arch_prctl", "bind", "brk",
+    "capget", "capset", "chdir", "chown", "clock_getres", "clock_gettime",
+    "clock_nanosleep", "close", "close_range", "connect", "copy_file_range", "creat",
+    "dup", "dup2", "dup3", "epoll_create", "epoll_create1", "epoll_ctl", "epoll_pwait",
+    "epoll_pwait2", "epoll_wait", "eventfd", "eventfd2", "execve", "execveat", "exit",
+    "exit_group", "faccessat", "faccessat2", "fadvise64", "fallocate", "fanotify_mark",
+    "fchdir", "fchown", "fchownat", "fcntl", "fdatasync", "fgetxattr", "flistxattr",
+    "flock", "fork", "fremovexattr", "fsetxattr", "fstat", "fstatfs", "fsync",
+    "ftruncate", "futex", "futex_waitv", "futimesat", "getcpu", "getcwd", "getdents",
+    "getdents64", "getegid", "geteuid", "getgid", "getgroups", "getitimer",
+    "getpeername", "getpgid", "getpgrp", "getpid", "getppid", "getpriority",
+    "getrandom", "getresgid", "getresuid", "getrlimit", "get_robust_list", "getrusage",
+    "getsid", "getsockname", "getsockopt", "get_thread_area", "gettid", "gettimeofday",
+    "getuid", "getxattr", "inotify_add_watch", "inotify_init", "inotify_init1",
+    "inotify_rm_watch", "io_cancel", "io_destroy", "io_getevents", "io_pgetevents",
+    "ioprio_get", "ioprio_set", "io_setup", "io_submit", "io_uring_enter",
+    "io_uring_register", "io_uring_setup", "kill", "landlock_add_rule",
+    "landlock_create_ruleset", "landlock_restrict_self", "lchown", "lgetxattr", "link",
+    "linkat", "listen", "listxattr", "llistxattr", "lremovexattr", "lseek", "lsetxattr",
+    "lstat", "madvise", "membarrier", "memfd_create", "memfd_secret", "mincore",
+    "mkdir", "mkdirat", "mknod", "mknodat", "mlock", "mlock2", "mlockall", "mmap",
+    "modify_ldt", "mprotect", "mq_getsetattr", "mq_notify", "mq_open",
+    "mq_timedreceive", "mq_timedsend", "mq_unlink", "mremap", "msgctl", "msgget",
+    "msgrcv", "msgsnd", "msync", "munlock", "munlockall", "munmap", "name_to_handle_at",
+    "nanosleep", "newfstatat", "open", "openat", "openat2", "pause", "pidfd_open",
+    "pidfd_send_signal", "pipe", "pipe2", "pkey_alloc", "pkey_free", "pkey_mprotect",
+    "poll", "ppoll", "prctl", "pread64", "preadv", "preadv2", "prlimit64",
+    "process_mrelease", "pselect6", "pwrite64", "pwritev", "pwritev2", "read",
+    "readahead", "readlink", "readlinkat", "readv", "recvfrom", "recvmmsg", "recvmsg",
+    "remap_file_pages", "removexattr", "rename", "renameat", "renameat2",
+    "restart_syscall", "rmdir", "rseq", "rt_sigaction", "rt_sigpending",
+    "rt_sigprocmask", "rt_sigqueueinfo", "rt_sigreturn", "rt_sigsuspend",
+    "rt_sigtimedwait", "rt_tgsigqueueinfo", "sched_getaffinity", "sched_getattr",
+    "sched_getparam", "sched_get_priority_max", "sched_get_priority_min",
+    "sched_getscheduler", "sched_rr_get_interval", "sched_setaffinity",
+    "sched_setattr", "sched_setparam", "sched_setscheduler", "sched_yield", "seccomp",
+    "select", "semctl", "semget", "semop", "semtimedop", "sendfile", "sendmmsg",
+    "sendmsg", "sendto", "setfsgid", "setfsuid", "setgid", "setgroups", "setitimer",
+    "setpgid", "setpriority", "setregid", "setresgid", "setresuid", "setreuid",
+    "setrlimit", "set_robust_list", "setsid", "setsockopt", "set_thread_area",
+    "set_tid_address", "setuid", "setxattr", "shmat", "shmctl", "shmdt", "shmget",
+    "shutdown", "sigaltstack", "signalfd", "signalfd4", "socket", "socketpair",
+    "splice", "stat", "statfs", "statx", "symlink", "symlinkat", "sync",
+    "sync_file_range", "syncfs", "sysinfo", "tee", "tgkill", "time", "timer_create",
+    "timer_delete", "timer_getoverrun", "timer_gettime", "timer_settime",
+    "timerfd_create", "timerfd_gettime", "timerfd_settime", "times", "tkill",
+    "truncate", "umask", "uname", "unlink", "unlinkat", "utime", "utimensat", "utimes",
+    "vfork", "vmsplice", "wait4", "waitid", "write", "writev",
+];
+
+// Syscalls only allowed along with the capability that guards them
+const SYSCALLS_WITH_CAP: [(Cap, &[&str]); 11] = [
+    (Cap::SYS_ADMIN, &[
+        "mount", "umount2", "pivot_root", "setns", "sethostname", "setdomainname",
+        "quotactl", "fanotify_init",
+    ]),
+    (Cap::SYS_PTRACE, &["ptrace", "process_vm_readv", "process_vm_writev", "kcmp"]),
+    (Cap::SYS_CHROOT, &["chroot"]),
+    (Cap::SYS_TIME, &["settimeofday", "clock_settime", "clock_adjtime"]),
+    (Cap::SYS_BOOT, &["reboot"]),
+    (Cap::SYSLOG, &["syslog"]),
+    (Cap::SYS_PACCT, &["acct"]),
+    (Cap::SYS_RAWIO, &["iopl", "ioperm"]),
+    (Cap::SYS_MODULE, &["init_module", "finit_module", "delete_module"]),
+    (Cap::DAC_READ_SEARCH, &["open_by_handle_at"]),
+    (Cap::SYS_NICE, &["get_mempolicy"]),
+];
+
+// Conditional syscall deny
+fn syscalls_refuse_ifcomp() -> [(&'static str, u32, u64); 8] {
     let s_isuid: u64 = Mode::S_ISUID.bits().into();
     let s_isgid: u64 = Mode::S_ISGID.bits().into();
     let clone_new_user: u64 = CloneFlags::CLONE_NEWUSER.bits() as u64;
+    [
+        ("chmod", 1, s_isuid),
+        ("chmod", 1, s_isgid),
+        ("fchmod", 1, s_isuid),
+        ("fchmod", 1, s_isgid),
+        ("fchmodat", 2, s_isuid),
+        ("fchmodat", 2, s_isgid),
+        ("unshare", 0, clone_new_user),
+        ("clone", 0, clone_new_user),
+    ]
+}
 
-    // Unconditional syscall deny
-    let syscalls_refused = [
-        Syscall::keyctl,
-        Syscall::add_key,
-        Syscall::request_key,
-        Syscall::mbind,
-        Syscall::migrate_pages,
-        Syscall::move_pages,
-        Syscall::set_mempolicy,
-        Syscall::userfaultfd,
-        Syscall::perf_event_open,
-    ];
+fn rule(names: &[&str], action: ScmpAction, args: Vec<ScmpArgCompare>) -> FilterRule {
+    FilterRule {
+        names: names.iter().map(|s| s.to_string()).collect(),
+        action,
+        args,
+    }
+}
+
+fn masked_eq(ind: u32, mask: u64, datum: u64) -> ScmpArgCompare {
+    ScmpArgCompare::new(ind, ScmpCompareOp::MaskedEqual(mask), datum)
+}
+
+// The native architecture and the compat ones its kernel runs, e.g. i386
+// binaries or int 0x80 on x86_64. Syscall numbers differ between them, so
+// each needs its own rules, and any other architecture is killed.
+pub fn native_arches() -> Vec<ScmpArch> {
+    let native = ScmpArch::native();
+    let compat: &[ScmpArch] = match native {
+        ScmpArch::X8664 => &[ScmpArch::X86, ScmpArch::X32],
+        ScmpArch::Aarch64 => &[ScmpArch::Arm],
+        _ => &[],
+    };
+    let mut arches = vec![native];
+    arches.extend_from_slice(compat);
+    arches
+}
+
+pub fn builtin_filter(mode: SeccompMode, caps: &CapabilitySets) -> SeccompFilter {
+    match mode {
+        SeccompMode::Denylist => denylist_filter(),
+        SeccompMode::Allowlist => allowlist_filter(caps),
+    }
+}
+
+// Built-in profile: everything allowed except a deny list
+fn denylist_filter() -> SeccompFilter {
+    let mut rules = vec![rule(&SYSCALLS_REFUSED, ScmpAction::Errno(EPERM), vec![])];
+    for (sc, ind, biteq) in syscalls_refuse_ifcomp().iter() {
+        rules.push(rule(&[sc], ScmpAction::Errno(EPERM), vec![masked_eq(*ind, *biteq, *biteq)]));
+    }
+    rules.push(rule(&["ioctl"], ScmpAction::Errno(EPERM), vec![masked_eq(1, TIOCSTI, TIOCSTI)]));
 
-    // Conditional syscall deny
-    let syscalls_refuse_ifcomp = [
-        (Syscall::chmod, 1, s_isuid),
-        (Syscall::chmod, 1, s_isgid),
-        (Syscall::fchmod, 1, s_isuid),
-        (Syscall::fchmod, 1, s_isgid),
-        (Syscall::fchmodat, 2, s_isuid),
-        (Syscall::fchmodat, 2, s_isgid),
-        (Syscall::unshare, 0, clone_new_user),
-        (Syscall::clone, 0, clone_new_user),
-        (Syscall::ioctl, 1, TIOCSTI),
+    SeccompFilter {
+        default_action: ScmpAction::Allow,
+        architectures: native_arches(),
+        log: false,
+        spec_allow: false,
+        rules,
+    }
+}
+
+// Built-in profile: unknown syscalls fail with ENOSYS so that libc falls
+// back to older ones (e.g. clone3 to clone), known dangerous ones with EPERM
+fn allowlist_filter(caps: &CapabilitySets) -> SeccompFilter {
+    let mut rules = vec![
+        rule(SYSCALLS_ALLOWED, ScmpAction::Allow, vec![]),
+        rule(&SYSCALLS_REFUSED, ScmpAction::Errno(EPERM), vec![]),
     ];
 
-    // Initialize seccomp profile with
-    // all syscalls allowed by default
-    if let Ok(mut ctx) = Context::init_with_action(Action::Allow) {
-        for (sc, ind, biteq) in syscalls_refuse_ifcomp.iter() {
-            refuse_if_comp(&mut ctx, *ind, sc, *biteq)?;
+    for (cap, names) in SYSCALLS_WITH_CAP.iter() {
+        let action = if caps.bounding.has(*cap) {
+            ScmpAction::Allow
+        } else {
+            ScmpAction::Errno(EPERM)
+        };
+        rules.push(rule(names, action, vec![]));
+    }
+
+    // An unconditional allow would take precedence over the conditional
+    // denies, so only allow when none of the denied bits is set
+    let refuse_ifcomp = syscalls_refuse_ifcomp();
+    for (sc, ind, biteq) in refuse_ifcomp.iter() {
+        rules.push(rule(&[sc], ScmpAction::Errno(EPERM), vec![masked_eq(*ind, *biteq, *biteq)]));
+    }
+    for sc in ["chmod", "fchmod", "fchmodat", "unshare", "clone"] {
+        let (ind, mask) = refuse_ifcomp
+            .iter()
+            .filter(|(name, _, _)| *name == sc)
+            .fold((0, 0), |(_, mask), (_, ind, biteq)| (*ind, mask | biteq));
+        rules.push(rule(&[sc], ScmpAction::Allow, vec![masked_eq(ind, mask, 0)]));
+    }
+    let tiocsti = |op| vec![ScmpArgCompare::new(1, op, TIOCSTI)];
+    rules.push(rule(&["ioctl"], ScmpAction::Errno(EPERM), tiocsti(ScmpCompareOp::Equal)));
+    rules.push(rule(&["ioctl"], ScmpAction::Allow, tiocsti(ScmpCompareOp::NotEqual)));
+
+    // Only the execution domains Docker allows
+    for persona in [0x0, 0x8, 0x20000, 0x20008, 0xffffffff] {
+        rules.push(rule(
+            &["personality"],
+            ScmpAction::Allow,
+            vec![ScmpArgCompare::new(0, ScmpCompareOp::Equal, persona)],
+        ));
+    }
+
+    SeccompFilter {
+        default_action: ScmpAction::Errno(ENOSYS),
+        architectures: native_arches(),
+        log: false,
+        spec_allow: false,
+        rules,
+    }
+}
+
+// Every syscall goes through the supervisor, which records it
+pub fn learn_filter() -> SeccompFilter {
+    SeccompFilter {
+        default_action: ScmpAction::Notify,
+        architectures: native_arches(),
+        log: false,
+        spec_allow: false,
+        rules: vec![],
+    }
+}
+
+// libseccomp only passes on the errno of a few failures of the kernel
+fn seccomp_errno(e: &SeccompError) -> Errno {
+    match e.errno() {
+        Some(SeccompErrno::EACCES) => Errno::EACCES,
+        Some(SeccompErrno::ECANCELED) => Errno::ECANCELED,
+        Some(SeccompErrno::EDOM) => Errno::EDOM,
+        Some(SeccompErrno::EEXIST) => Errno::EEXIST,
+        Some(SeccompErrno::EFAULT) => Errno::EFAULT,
+        Some(SeccompErrno::EINVAL) => Errno::EINVAL,
+        Some(SeccompErrno::ENOENT) => Errno::ENOENT,
+        Some(SeccompErrno::ENOMEM) => Errno::ENOMEM,
+        Some(SeccompErrno::EOPNOTSUPP) => Errno::EOPNOTSUPP,
+        Some(SeccompErrno::ERANGE) => Errno::ERANGE,
+        Some(SeccompErrno::ESRCH) => Errno::ESRCH,
+        _ => Errno::UnknownErrno,
+    }
+}
+
+fn resolve_syscall(name: &str) -> Result<ScmpSyscall, ErrCode> {
+    match ScmpSyscall::from_name(name) {
+        Ok(sc) => Ok(sc),
+        Err(_) => {
+            log::error!("Unknown syscall {}", name);
+            Err(ErrCode::SyscallsError(2))
         }
+    }
+}
 
-        for sc in syscalls_refused.iter() {
-            refuse_syscall(&mut ctx, sc)?;
+fn add_rule(ctx: &mut ScmpFilterContext, rule: &FilterRule, sc: ScmpSyscall) -> Result<(), ErrCode> {
+    if rule.args.is_empty() {
+        match ctx.add_rule(rule.action, sc) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrCode::SyscallsError(2)),
         }
+    } else {
+        match ctx.add_rule_conditional(rule.action, sc, &rule.args) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrCode::SyscallsError(3)),
+        }
+    }
+}
 
-        if let Err(_) = ctx.load() {
-            return Err(ErrCode::SyscallsError(0));
+// Hands the notification listener over to the supervisor in the parent
+#[cfg(seccomp_notify)]
+fn send_listener(ctx: &ScmpFilterContext, fd: RawFd) -> Result<(), SetupError> {
+    let listener = match ctx.get_notify_fd() {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Cannot get seccomp notification fd: {}", e);
+            return Err(ErrCode::SyscallsError(5).caused_by(seccomp_errno(&e)));
         }
+    };
+    send_fd(fd, FdKind::SeccompNotify, listener)?;
+    if let Err(e) = close(listener) {
+        return Err(ErrCode::SyscallsError(5).caused_by(e));
+    }
+    Ok(())
+}
 
-        Ok(())
-    } else {
-        Err(ErrCode::SyscallsError(1))
+// Translates the filter for every architecture, without loading it
+fn build_filter(filter: &SeccompFilter, fd: RawFd) -> Result<ScmpFilterContext, ErrCode> {
+    let mut ctx = match ScmpFilterContext::new_filter(filter.default_action) {
+        Ok(ctx) => ctx,
+        Err(_) => return Err(ErrCode::SyscallsError(1)),
+    };
+
+    // no_new_privs is handled by the caller, without it loading the
+    // filter requires CAP_SYS_ADMIN
+    if ctx.set_ctl_nnp(false).is_err()
+        || ctx.set_ctl_log(filter.log).is_err()
+        || (filter.spec_allow && ctx.set_ctl_ssb(true).is_err())
+        || ctx.set_act_badarch(ScmpAction::KillThread).is_err()
+    {
+        return Err(ErrCode::SyscallsError(1));
+    }
+
+    for arch in filter.architectures.iter() {
+        if let Err(e) = ctx.add_arch(*arch) {
+            log::error!("Cannot add seccomp architecture {:?}: {}", arch, e);
+            return Err(ErrCode::SyscallsError(4));
+        }
+    }
+
+    for rule in filter.rules.iter() {
+        // libseccomp refuses rules that do the same as the default action
+        if rule.action == filter.default_action {
+            continue;
+        }
+        for name in rule.names.iter() {
+            // Profiles list syscalls of newer kernels or other architectures
+            match ScmpSyscall::from_name(name) {
+                Ok(sc) => add_rule(&mut ctx, rule, sc)?,
+                Err(_) => log::warn!("Unknown syscall {}, ignoring its rule", name),
+            }
+        }
+    }
+
+    // Sending the listener must not wait for the supervisor it goes to
+    if filter.uses_notify() && filter.default_action != ScmpAction::Allow {
+        let sendmsg = resolve_syscall("sendmsg")?;
+        let on_socket = ScmpArgCompare::new(0, ScmpCompareOp::Equal, fd as u64);
+        if ctx.add_rule_conditional(ScmpAction::Allow, sendmsg, &[on_socket]).is_err() {
+            return Err(ErrCode::SyscallsError(3));
+        }
+    }
+    Ok(ctx)
+}
+
+pub fn setsyscalls(filter: &SeccompFilter, fd: RawFd) -> Result<(), SetupError> {
+    log::debug!("Filtering unwanted syscalls");
+    let ctx = build_filter(filter, fd)?;
+
+    if let Err(e) = ctx.load() {
+        log::error!("Cannot load seccomp filter: {}", e);
+        return Err(ErrCode::SyscallsError(0).caused_by(seccomp_errno(&e)));
+    }
+
+    #[cfg(seccomp_notify)]
+    if filter.uses_notify() {
+        send_listener(&ctx, fd)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capctl::caps::CapSet;
+
+    fn caps() -> CapabilitySets {
+        CapabilitySets {
+            bounding: CapSet::empty(),
+            effective: CapSet::empty(),
+            permitted: CapSet::empty(),
+            inheritable: CapSet::empty(),
+            ambient: CapSet::empty(),
+        }
+    }
+
+    // Pseudo filter code libseccomp generates, one section per architecture
+    fn pfc(filter: &SeccompFilter) -> String {
+        let ctx = build_filter(filter, 3).unwrap();
+        let path = std::env::temp_dir().join(format!("crabcan-pfc-{}", std::process::id()));
+        ctx.export_pfc(&mut std::fs::File::create(&path).unwrap()).unwrap();
+        let pfc = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        pfc
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn builtin_filters_cover_compat_arches() {
+        for mode in [SeccompMode::Denylist, SeccompMode::Allowlist] {
+            let filter = builtin_filter(mode, &caps());
+            assert_eq!(
+                filter.architectures,
+                vec![ScmpArch::X8664, ScmpArch::X86, ScmpArch::X32]
+            );
+            let ctx = build_filter(&filter, 3).unwrap();
+            for arch in filter.architectures.iter() {
+                assert!(ctx.is_arch_present(*arch).unwrap());
+            }
+            assert_eq!(ctx.get_act_badarch().unwrap(), ScmpAction::KillThread);
+
+            // The rules are translated for every architecture
+            let pfc = pfc(&filter);
+            for arch in ["x86_64", "x86", "x32"] {
+                assert!(pfc.contains(&format!("# filter for arch {} ", arch)), "{}", arch);
+            }
+        }
+    }
+
+    #[test]
+    fn learn_filter_notifies_every_arch() {
+        let filter = learn_filter();
+        assert_eq!(filter.architectures[0], ScmpArch::native());
+        let ctx = build_filter(&filter, 3).unwrap();
+        for arch in filter.architectures.iter() {
+            assert!(ctx.is_arch_present(*arch).unwrap());
+        }
     }
 }
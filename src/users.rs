@@ -0,0 +1,171 @@
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+use crate::errors::ErrCode;
+use crate::namespaces::UsernsRange;
+
+// --user NAME|UID[:GROUP|GID], only known once in the new root as it is
+// looked up in the /etc/passwd and /etc/group of the container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserSpec {
+    pub user: String,
+    pub group: Option<String>,
+}
+
+impl FromStr for UserSpec {
+    type Err = ErrCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        if user.is_empty() || group.is_some_and(|g| g.is_empty() || g.contains(':')) {
+            return Err(ErrCode::InvalidArgument("user"));
+        }
+        Ok(UserSpec {
+            user: user.to_string(),
+            group: group.map(|g| g.to_string()),
+        })
+    }
+}
+
+impl UserSpec {
+    pub fn from_uid(uid: u32) -> UserSpec {
+        UserSpec {
+            user: uid.to_string(),
+            group: None,
+        }
+    }
+}
+
+// Who the container process runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    // Supplementary groups, starting with the primary one
+    pub groups: Vec<u32>,
+    pub home: Option<String>,
+}
+
+struct PasswdEntry {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+struct GroupEntry {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+// Lines of a colon separated file of the container, none if it has no such
+// file, malformed lines are skipped
+fn read_entries<T>(path: &str, parse: fn(&[&str]) -> Option<T>) -> Vec<T> {
+    match read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .filter_map(|l| parse(&l.split(':').collect::<Vec<_>>()))
+            .collect(),
+        Err(e) => {
+            log::debug!("No {} in the container: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+fn parse_passwd(fields: &[&str]) -> Option<PasswdEntry> {
+    match fields {
+        [name, _, uid, gid, _, home, ..] => Some(PasswdEntry {
+            name: name.to_string(),
+            uid: uid.parse().ok()?,
+            gid: gid.parse().ok()?,
+            home: home.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+fn parse_group(fields: &[&str]) -> Option<GroupEntry> {
+    match fields {
+        [name, _, gid, members] => Some(GroupEntry {
+            name: name.to_string(),
+            gid: gid.parse().ok()?,
+            members: members
+                .split(',')
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_string())
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+// Like docker, numeric ids don't need an entry in the files, a uid without
+// one runs with the group of the same id. The ids have to be mapped in the
+// user namespace, supplementary groups that aren't are left out.
+pub fn resolve_user(spec: &UserSpec, range: UsernsRange) -> Result<User, ErrCode> {
+    let passwd = read_entries("/etc/passwd", parse_passwd);
+    let groups = read_entries("/etc/group", parse_group);
+
+    let (uid, entry) = match spec.user.parse::<u32>() {
+        Ok(uid) => (uid, passwd.iter().find(|e| e.uid == uid)),
+        Err(_) => match passwd.iter().find(|e| e.name == spec.user) {
+            Some(entry) => (entry.uid, Some(entry)),
+            None => {
+                log::error!("No user {} in /etc/passwd of the container", spec.user);
+                return Err(ErrCode::NamespaceError(10));
+            }
+        },
+    };
+
+    let gid = match &spec.group {
+        None => entry.map_or(uid, |e| e.gid),
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => match groups.iter().find(|g| &g.name == group) {
+                Some(g) => g.gid,
+                None => {
+                    log::error!("No group {} in /etc/group of the container", group);
+                    return Err(ErrCode::NamespaceError(11));
+                }
+            },
+        },
+    };
+
+    for (kind, id) in [("uid", uid), ("gid", gid)] {
+        if !range.contains(id) {
+            log::error!(
+                "The {} {} is not mapped in the user namespace of the container, only ids \
+                 below {} are (see --userns-range)",
+                kind,
+                id,
+                range.count
+            );
+            return Err(ErrCode::NamespaceError(12));
+        }
+    }
+
+    let mut supplementary = vec![gid];
+    if let Some(entry) = entry {
+        for group in groups.iter().filter(|g| g.members.contains(&entry.name)) {
+            if !range.contains(group.gid) {
+                log::warn!(
+                    "Leaving out group {}, not mapped in the container",
+                    group.name
+                );
+            } else if !supplementary.contains(&group.gid) {
+                supplementary.push(group.gid);
+            }
+        }
+    }
+    Ok(User {
+        uid,
+        gid,
+        groups: supplementary,
+        home: entry.map(|e| e.home.clone()),
+    })
+}
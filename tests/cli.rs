@@ -1,8 +1,79 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
+use std::fs::{create_dir_all, read_dir, remove_dir, remove_file};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
 
+const HOST_DIRS: [&str; 5] = ["/bin", "/lib", "/lib64", "/usr", "/proc"];
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Runs a command in a container whose rootfs is made of the host's binaries,
+// an empty command is given after -- in extra instead
+fn run_container(
+    name: &str,
+    uid: u32,
+    command: &str,
+    extra: &[&str],
+) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+    let rootfs = std::env::temp_dir().join(format!("crabcan-test-{}-{}", name, std::process::id()));
+    create_dir_all(&rootfs)?;
+
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    if !command.is_empty() {
+        cmd.args(["-c", command]);
+    }
+    // --user replaces -u
+    if !extra.contains(&"--user") {
+        cmd.args(["-u", &uid.to_string()]);
+    }
+    cmd.arg("-m").arg(&rootfs);
+    for dir in HOST_DIRS.iter().filter(|d| Path::new(d).exists()) {
+        cmd.args(["-a", &format!("{}:{}", dir, dir)]);
+    }
+    let output = cmd.args(extra).output()?;
+
+    // Only empty mountpoints and files created by the test are left behind,
+    // never remove recursively
+    for entry in read_dir(&rootfs)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_dir(entry.path()).ok();
+        } else {
+            remove_file(entry.path()).ok();
+        }
+    }
+    remove_dir(&rootfs).ok();
+    Ok(output)
+}
+
+fn run_in_container(
+    name: &str,
+    uid: u32,
+    command: &str,
+    extra: &[&str],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let output = run_container(name, uid, command, extra)?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn status_field<'a>(status: &'a str, field: &str) -> Option<&'a str> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix(field)?.strip_prefix(':'))
+        .map(|v| v.trim())
+}
+
 #[test]
 fn no_arguments() -> TestResult {
     let mut cmd = Command::cargo_bin("crabcan")?;
@@ -10,4 +81,1205 @@ fn no_arguments() -> TestResult {
         .failure()
         .stderr(predicate::str::contains("USAGE"));
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn invalid_command() -> TestResult {
+    for command in [
+        vec!["-c", ""],
+        vec!["-c", "/bin/echo 'unterminated"],
+        vec!["-c", "/bin/true", "--", "/bin/true"],
+        vec!["--", ""],
+    ] {
+        let mut cmd = Command::cargo_bin("crabcan")?;
+        cmd.args(["-u", "0", "-m", "/tmp"])
+            .args(command)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("InvalidArgument(\"command\")"));
+    }
+    Ok(())
+}
+
+#[test]
+fn command_arguments_kept_whole() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    // Passed verbatim after --, the program is looked up in PATH
+    let stdout = run_in_container(
+        "argv",
+        0,
+        "",
+        &["--", "sh", "-c", "echo \"[$1]\"", "sh", "arg  two"],
+    )?;
+    assert!(stdout.contains("[arg  two]"), "{}", stdout);
+
+    let stdout = run_in_container("quoted", 0, "echo 'a  b' \"c\\\"d\" e\\ f", &[])?;
+    assert!(stdout.contains("a  b c\"d e f"), "{}", stdout);
+    Ok(())
+}
+
+#[test]
+fn unknown_network_mode() -> TestResult {
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args([
+        "-c",
+        "/bin/sh",
+        "-u",
+        "0",
+        "-m",
+        "/tmp",
+        "--network",
+        "bridge",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("InvalidArgument: network"));
+    Ok(())
+}
+
+#[test]
+fn publish_requires_slirp() -> TestResult {
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", "-p", "8080:80"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("InvalidArgument(\"publish\")"));
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args([
+        "-c",
+        "/bin/sh",
+        "-u",
+        "0",
+        "-m",
+        "/tmp",
+        "--allow-host-loopback",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains(
+        "InvalidArgument(\"allow-host-loopback\")",
+    ));
+    Ok(())
+}
+
+// Echoes what a single connection sends, until it closes its end
+fn echo(mut stream: std::net::TcpStream) -> std::io::Result<()> {
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf)?;
+    stream.write_all(&buf)
+}
+
+#[test]
+fn slirp_host_loopback() -> TestResult {
+    if !is_root() || !Path::new("/usr/bin/python3").exists() {
+        return Ok(());
+    }
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept()?;
+        echo(stream)
+    });
+
+    let client = format!(
+        "import socket
+try:
+    c = socket.create_connection(('10.0.2.2', {}), timeout=5)
+    c.sendall(b'ping')
+    c.shutdown(socket.SHUT_WR)
+    print(c.recv(100).decode())
+except OSError as e:
+    print(type(e).__name__)",
+        port
+    );
+    // The gateway leads nowhere by default
+    let output = run_in_container(
+        "no-loopback",
+        0,
+        "",
+        &[
+            "--network",
+            "slirp",
+            "--",
+            "/usr/bin/python3",
+            "-c",
+            &client,
+        ],
+    )?;
+    assert_eq!(output.trim(), "ConnectionRefusedError");
+
+    let output = run_in_container(
+        "loopback",
+        0,
+        "",
+        &[
+            "--network",
+            "slirp",
+            "--allow-host-loopback",
+            "--",
+            "/usr/bin/python3",
+            "-c",
+            &client,
+        ],
+    )?;
+    assert_eq!(output.trim(), "ping");
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn slirp_published_port() -> TestResult {
+    if !is_root() || !Path::new("/usr/bin/python3").exists() {
+        return Ok(());
+    }
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let publish = format!("{}:8080", port);
+    let container = std::thread::spawn(move || {
+        let server = "import socket
+s = socket.socket()
+s.bind(('0.0.0.0', 8080))
+s.listen()
+c, _ = s.accept()
+data = b''
+while True:
+    chunk = c.recv(100)
+    if not chunk:
+        break
+    data += chunk
+c.sendall(data)
+c.close()";
+        run_in_container(
+            "publish",
+            0,
+            "",
+            &[
+                "--network",
+                "slirp",
+                "-p",
+                &publish,
+                "--",
+                "/usr/bin/python3",
+                "-c",
+                server,
+            ],
+        )
+        .is_ok()
+    });
+
+    // Connections are closed until the container listens
+    let mut reply = String::new();
+    for _ in 0..100 {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            stream.write_all(b"ping")?;
+            stream.shutdown(Shutdown::Write)?;
+            reply.clear();
+            stream.read_to_string(&mut reply).ok();
+            if reply == "ping" {
+                break;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(reply, "ping");
+    assert!(container.join().unwrap());
+    Ok(())
+}
+
+#[test]
+fn unknown_capability() -> TestResult {
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args([
+        "-c",
+        "/bin/sh",
+        "-u",
+        "0",
+        "-m",
+        "/tmp",
+        "--cap-add",
+        "NET_FOO",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("InvalidArgument: cap-add"));
+    Ok(())
+}
+
+#[test]
+fn capabilities_applied_to_child() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let status = run_in_container(
+        "caps",
+        0,
+        "/bin/cat /proc/self/status",
+        &[
+            "--cap-drop",
+            "ALL",
+            "--cap-add",
+            "NET_BIND_SERVICE,CAP_CHOWN",
+        ],
+    )?;
+    assert_eq!(status_field(&status, "CapBnd"), Some("0000000000000401"));
+    assert_eq!(status_field(&status, "CapPrm"), Some("0000000000000401"));
+    assert_eq!(status_field(&status, "CapEff"), Some("0000000000000401"));
+    assert_eq!(status_field(&status, "CapInh"), Some("0000000000000000"));
+    assert_eq!(status_field(&status, "CapAmb"), Some("0000000000000000"));
+
+    // Dropping ALL wins over adding ALL
+    let status = run_in_container(
+        "caps-all",
+        0,
+        "/bin/cat /proc/self/status",
+        &["--cap-drop", "ALL", "--cap-add", "ALL"],
+    )?;
+    assert_eq!(status_field(&status, "CapBnd"), Some("0000000000000000"));
+    assert_eq!(status_field(&status, "CapEff"), Some("0000000000000000"));
+    Ok(())
+}
+
+#[test]
+fn ambient_capabilities_for_non_root() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let status = run_in_container(
+        "ambient",
+        1000,
+        "/bin/cat /proc/self/status",
+        &["--cap-ambient", "NET_BIND_SERVICE"],
+    )?;
+    assert_eq!(status_field(&status, "Uid"), Some("1000\t1000\t1000\t1000"));
+    assert_eq!(status_field(&status, "CapAmb"), Some("0000000000000400"));
+    assert_eq!(status_field(&status, "CapEff"), Some("0000000000000400"));
+    assert_eq!(status_field(&status, "CapPrm"), Some("0000000000000400"));
+    Ok(())
+}
+
+#[test]
+fn no_new_privs_by_default() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let status = run_in_container("nnp", 0, "/bin/cat /proc/self/status", &[])?;
+    assert_eq!(status_field(&status, "NoNewPrivs"), Some("1"));
+    assert_eq!(status_field(&status, "Seccomp"), Some("2"));
+
+    let status = run_in_container(
+        "new-privs",
+        0,
+        "/bin/cat /proc/self/status",
+        &["--allow-new-privs"],
+    )?;
+    assert_eq!(status_field(&status, "NoNewPrivs"), Some("0"));
+    assert_eq!(status_field(&status, "Seccomp"), Some("2"));
+    Ok(())
+}
+
+#[test]
+fn noroot_securebit() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    // Root gets no capabilities on execve, only ambient ones are kept
+    let status = run_in_container(
+        "noroot",
+        0,
+        "/bin/cat /proc/self/status",
+        &[
+            "--securebits",
+            "SECBIT_NOROOT,noroot_locked",
+            "--cap-ambient",
+            "CHOWN",
+        ],
+    )?;
+    assert_eq!(status_field(&status, "CapPrm"), Some("0000000000000001"));
+    assert_eq!(status_field(&status, "CapEff"), Some("0000000000000001"));
+
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args([
+        "-c",
+        "/bin/sh",
+        "-u",
+        "0",
+        "-m",
+        "/tmp",
+        "--securebits",
+        "KEEP_CAPS",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("InvalidArgument: securebits"));
+    Ok(())
+}
+
+fn write_profile(
+    name: &str,
+    profile: &str,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("crabcan-{}-{}.json", name, std::process::id()));
+    std::fs::write(&path, profile)?;
+    Ok(path)
+}
+
+#[test]
+fn seccomp_profile_applied() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let profile = write_profile(
+        "deny-uname",
+        r#"{
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "architectures": ["SCMP_ARCH_X86_64"],
+            "syscalls": [
+                {"names": ["uname"], "action": "SCMP_ACT_ERRNO", "errnoRet": 1},
+                {"names": ["getpid"], "action": "SCMP_ACT_ERRNO", "includes": {"caps": ["CAP_SYS_ADMIN"]}}
+            ]
+        }"#,
+    )?;
+    let output = run_in_container(
+        "seccomp-profile",
+        0,
+        "/bin/uname",
+        &["--seccomp-profile", profile.to_str().unwrap()],
+    );
+    std::fs::remove_file(&profile)?;
+    assert_eq!(output?, "");
+    assert_eq!(
+        run_in_container("no-seccomp-profile", 0, "/bin/uname", &[])?,
+        "Linux\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn unsupported_seccomp_profile() -> TestResult {
+    let profile = write_profile(
+        "notify",
+        r#"{"defaultAction": "SCMP_ACT_ALLOW", "listenerPath": "/run/seccomp.sock"}"#,
+    )?;
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    let assert = cmd
+        .args([
+            "-c",
+            "/bin/sh",
+            "-u",
+            "0",
+            "-m",
+            "/tmp",
+            "--seccomp-profile",
+        ])
+        .arg(&profile)
+        .assert();
+    std::fs::remove_file(&profile)?;
+    assert
+        .failure()
+        .stderr(predicate::str::contains("InvalidArgument: seccomp-profile"));
+    Ok(())
+}
+
+#[test]
+fn seccomp_allowlist_mode() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    // The curated set is enough for a dynamically linked program
+    let status = run_in_container(
+        "allowlist",
+        1000,
+        "/bin/cat /proc/self/status",
+        &["--seccomp-mode", "allowlist"],
+    )?;
+    assert_eq!(status_field(&status, "Seccomp"), Some("2"));
+
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args([
+        "-c",
+        "/bin/sh",
+        "-u",
+        "0",
+        "-m",
+        "/tmp",
+        "--seccomp-mode",
+        "allowlist",
+        "--seccomp-profile",
+        "/dev/null",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("seccomp-mode"));
+    Ok(())
+}
+
+#[test]
+#[cfg(seccomp_notify)]
+fn seccomp_learn_mode() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let profile = std::env::temp_dir().join(format!("crabcan-learn-{}.json", std::process::id()));
+    run_in_container(
+        "learn",
+        0,
+        "/bin/cat /proc/self/status",
+        &["--seccomp-learn", profile.to_str().unwrap()],
+    )?;
+    let learned = std::fs::read_to_string(&profile)?;
+    assert!(learned.contains("\"execve\""));
+    assert!(learned.contains("\"openat\""));
+
+    // The recorded profile is enough to run the same workload again
+    let status = run_in_container(
+        "learned",
+        0,
+        "/bin/cat /proc/self/status",
+        &["--seccomp-profile", profile.to_str().unwrap()],
+    );
+    std::fs::remove_file(&profile)?;
+    assert_eq!(status_field(&status?, "Seccomp"), Some("2"));
+    Ok(())
+}
+
+#[test]
+#[cfg(seccomp_notify)]
+fn seccomp_notify_policy() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let policy = write_profile(
+        "notify-policy",
+        r#"{
+            "rules": [
+                {"names": ["mknodat"], "decision": "emulate",
+                 "args": [{"index": 3, "value": 259, "op": "SCMP_CMP_EQ"}]},
+                {"names": ["uname"], "decision": "deny", "errnoRet": 1}
+            ]
+        }"#,
+    )?;
+    let scripts = std::env::temp_dir().join(format!("crabcan-scripts-{}", std::process::id()));
+    create_dir_all(&scripts)?;
+    // /dev/null can be created from the user namespace through the
+    // supervisor, other devices can't
+    std::fs::write(
+        scripts.join("run.sh"),
+        "mknod /null c 1 3 && stat -c %t:%T /null\n\
+         mknod /zero c 1 5 2>/dev/null || echo zero denied\n\
+         uname 2>/dev/null || echo uname denied\n",
+    )?;
+    let output = run_in_container(
+        "notify-policy",
+        0,
+        "/bin/sh /scripts/run.sh",
+        &[
+            "--seccomp-notify",
+            policy.to_str().unwrap(),
+            "-a",
+            &format!("{}:/scripts", scripts.display()),
+        ],
+    );
+    std::fs::remove_file(&policy)?;
+    remove_file(scripts.join("run.sh"))?;
+    remove_dir(&scripts)?;
+    assert_eq!(output?, "1:3\nzero denied\nuname denied\n");
+    Ok(())
+}
+
+#[test]
+#[cfg(seccomp_notify)]
+fn notify_policy_strings_not_allowed() -> TestResult {
+    let policy = write_profile(
+        "allow-strings",
+        r#"{
+            "rules": [
+                {"names": ["openat"], "decision": "allow",
+                 "strings": [{"index": 1, "values": ["/etc/hostname"]}]}
+            ]
+        }"#,
+    )?;
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    let assert = cmd
+        .args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", "--seccomp-notify"])
+        .arg(&policy)
+        .assert();
+    std::fs::remove_file(&policy)?;
+    assert
+        .failure()
+        .stderr(predicate::str::contains("strings in allow rule"))
+        .stderr(predicate::str::contains("InvalidArgument: seccomp-notify"));
+    Ok(())
+}
+
+#[test]
+#[cfg(not(seccomp_notify))]
+fn seccomp_notify_not_supported() -> TestResult {
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp"])
+        .args(["--seccomp-audit", "report"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("NotSupported(2)"));
+    Ok(())
+}
+
+#[test]
+#[cfg(seccomp_notify)]
+fn seccomp_audit_mode() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    // Denied by the built-in profile, let through and reported
+    let output = run_in_container(
+        "audit",
+        0,
+        "/usr/bin/unshare -U /usr/bin/id -u",
+        &["--seccomp-audit", "complain"],
+    )?;
+    assert_eq!(output, "65534\n");
+
+    let rootfs =
+        std::env::temp_dir().join(format!("crabcan-test-audit-report-{}", std::process::id()));
+    create_dir_all(&rootfs)?;
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args(["-c", "/usr/bin/unshare -U /bin/true", "-u", "0", "-m"])
+        .arg(&rootfs)
+        .args(["--seccomp-audit", "report"]);
+    for dir in HOST_DIRS.iter().filter(|d| Path::new(d).exists()) {
+        cmd.args(["-a", &format!("{}:{}", dir, dir)]);
+    }
+    let assert = cmd.assert();
+    for entry in read_dir(&rootfs)? {
+        remove_dir(entry?.path()).ok();
+    }
+    remove_dir(&rootfs)?;
+    assert
+        .success()
+        .stderr(predicate::str::contains("unshare failed"))
+        .stderr(predicate::str::contains("denied unshare 1 time(s)"));
+    Ok(())
+}
+
+#[test]
+fn resource_limit_options() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let output = run_in_container(
+        "limits",
+        0,
+        "/bin/echo ok",
+        &[
+            "--memory",
+            "512m",
+            "--memory-swap",
+            "-1",
+            "--cpus",
+            "0.5",
+            "--cpu-shares",
+            "512",
+            "--cpuset-cpus",
+            "0",
+            "--pids-limit",
+            "32",
+            "--blkio-weight",
+            "100",
+            "--ulimit",
+            "nofile=128:256",
+        ],
+    )?;
+    assert_eq!(output, "ok\n");
+
+    for (arg, value, error) in [
+        ("--memory", "1m", "memory"),
+        ("--memory-swap", "256m", "memory-swap"),
+        ("--cpus", "0", "cpus"),
+        ("--cpu-shares", "1", "cpu-shares"),
+        ("--cpuset-cpus", "3-1", "cpuset-cpus"),
+        ("--cpuset-cpus", "4096", "cpuset-cpus"),
+        ("--cpuset-mems", "4096", "cpuset-mems"),
+        ("--blkio-weight", "5", "blkio-weight"),
+        ("--device-read-bps", "/dev/null:1m", "device-read-bps"),
+        ("--ulimit", "nofile=256:128", "ulimit"),
+        ("--ulimit", "nothing=1", "ulimit"),
+    ] {
+        let mut cmd = Command::cargo_bin("crabcan")?;
+        cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", arg, value])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(error));
+    }
+    Ok(())
+}
+
+#[test]
+fn rlimits_in_container() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let limits = run_in_container(
+        "rlimits",
+        1000,
+        "/bin/cat /proc/self/limits",
+        &[
+            "--ulimit",
+            "nofile=100:200",
+            "--ulimit",
+            "core=0",
+            "--ulimit",
+            "nproc=50:60",
+        ],
+    )?;
+    let limit = |name: &str| {
+        limits.lines().find(|l| l.starts_with(name)).map(|l| {
+            l[name.len()..]
+                .split_whitespace()
+                .take(2)
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+    };
+    assert_eq!(limit("Max open files").as_deref(), Some("100:200"));
+    assert_eq!(limit("Max core file size").as_deref(), Some("0:0"));
+    assert_eq!(limit("Max processes").as_deref(), Some("50:60"));
+    Ok(())
+}
+
+#[test]
+fn cgroup_cleaned_up() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let parent = format!("crabcan-test-{}", std::process::id());
+    // Containers sharing a hostname get their own cgroup
+    for _ in 0..2 {
+        run_in_container(
+            "cgroup",
+            0,
+            "/bin/true",
+            &["-h", "same", "--cgroup-parent", &format!("/{}", parent)],
+        )?;
+    }
+    // Unified mode has a single hierarchy, v1 one per controller
+    let mut parents = vec![Path::new("/sys/fs/cgroup").join(&parent)];
+    for controller in read_dir("/sys/fs/cgroup")? {
+        parents.push(controller?.path().join(&parent));
+    }
+    for dir in parents.iter().filter(|d| d.exists()) {
+        let leftover = read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_type().is_ok_and(|t| t.is_dir()));
+        remove_dir(dir)?;
+        assert!(!leftover, "cgroup left in {}", dir.display());
+    }
+
+    for (arg, value) in [
+        ("--cgroup-parent", "crabcan"),
+        ("--cgroup-parent", "/crabcan/../.."),
+        ("--hostname", "../escape"),
+    ] {
+        let mut cmd = Command::cargo_bin("crabcan")?;
+        cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", arg, value])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(&arg[2..]));
+    }
+    Ok(())
+}
+
+// State file of the started container with this hostname
+fn container_state(hostname: &str) -> Option<serde_json::Value> {
+    read_dir("/run/crabcan").ok()?.find_map(|entry| {
+        let state = std::fs::read_to_string(entry.ok()?.path().join("state.json")).ok()?;
+        let state: serde_json::Value = serde_json::from_str(&state).ok()?;
+        if state["hostname"] != hostname || !state["pid"].is_i64() {
+            return None;
+        }
+        Some(state)
+    })
+}
+
+// Id of a container started from another thread, once it is running
+fn wait_for_container(hostname: &str) -> String {
+    for _ in 0..20 {
+        if let Some(state) = container_state(hostname) {
+            return state["id"].as_str().unwrap().to_string();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("container {} not found", hostname);
+}
+
+#[test]
+fn stats_of_running_container() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let hostname = format!("stats{}", std::process::id());
+    let container = {
+        let hostname = hostname.clone();
+        std::thread::spawn(move || {
+            run_in_container("stats", 0, "/bin/sleep 3", &["-h", &hostname]).is_ok()
+        })
+    };
+
+    let id = wait_for_container(&hostname);
+    let output = Command::cargo_bin("crabcan")?
+        .args(["stats", "--json", &id])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(stats["id"], id.as_str());
+    assert!(stats["pids"].as_u64().is_some_and(|p| p >= 1));
+    assert!(stats["memoryUsage"].as_u64().is_some_and(|m| m > 0));
+    assert!(stats["networks"]["lo"].is_object());
+
+    assert!(container.join().unwrap());
+    // Stopped containers have no stats
+    Command::cargo_bin("crabcan")?
+        .args(["stats", "--json", &id])
+        .assert()
+        .failure();
+    Command::cargo_bin("crabcan")?
+        .args(["stats", "../escape"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("container id"));
+    Ok(())
+}
+
+#[test]
+fn oom_kill_and_pids_limit_reported() -> TestResult {
+    if !is_root() || !Path::new("/usr/bin/python3").exists() {
+        return Ok(());
+    }
+    let output = run_container(
+        "oom",
+        0,
+        "/usr/bin/python3 -c \"b=b'a'*(512<<20)\"",
+        &["--memory", "32m", "--memory-swap", "32m"],
+    )?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr);
+    assert!(stderr.contains("OOM killer killed 1 process"), "{}", stderr);
+
+    // The shell and two sleeps fill the pids limit, the shell gives up on
+    // the third fork
+    let scripts = std::env::temp_dir().join(format!("crabcan-pids-{}", std::process::id()));
+    create_dir_all(&scripts)?;
+    std::fs::write(
+        scripts.join("run.sh"),
+        "sleep 1 & sleep 1 & sleep 1 &\nwait\n",
+    )?;
+    let output = run_container(
+        "pids",
+        0,
+        "/bin/sh /scripts/run.sh",
+        &[
+            "--pids-limit",
+            "3",
+            "-a",
+            &format!("{}:/scripts", scripts.display()),
+            "-a",
+            "/dev:/dev",
+        ],
+    );
+    remove_file(scripts.join("run.sh"))?;
+    remove_dir(&scripts)?;
+    let output = output?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("1 fork(s) failed on the pids limit"),
+        "{}",
+        stderr
+    );
+    Ok(())
+}
+
+#[test]
+fn pause_and_resume() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let hostname = format!("pause{}", std::process::id());
+    let container = {
+        let hostname = hostname.clone();
+        std::thread::spawn(move || {
+            run_in_container("pause", 0, "/bin/sleep 1", &["-h", &hostname]).is_ok()
+        })
+    };
+    let id = wait_for_container(&hostname);
+
+    Command::cargo_bin("crabcan")?
+        .args(["pause", &id])
+        .assert()
+        .success();
+    assert_eq!(container_state(&hostname).unwrap()["status"], "paused");
+    Command::cargo_bin("crabcan")?
+        .args(["pause", &id])
+        .assert()
+        .failure();
+    // Frozen, the sleep can't end
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(!container.is_finished());
+
+    Command::cargo_bin("crabcan")?
+        .args(["resume", &id])
+        .assert()
+        .success();
+    assert!(container.join().unwrap());
+    Command::cargo_bin("crabcan")?
+        .args(["resume", &id])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn stats_file_written_on_exit() -> TestResult {
+    if !is_root() || !Path::new("/usr/bin/python3").exists() {
+        return Ok(());
+    }
+    let stats_file =
+        std::env::temp_dir().join(format!("crabcan-stats-{}.json", std::process::id()));
+    run_in_container(
+        "stats-file",
+        0,
+        "/usr/bin/python3 -c \"b=b'a'*(32<<20)\"",
+        &["--stats-file", stats_file.to_str().unwrap()],
+    )?;
+    let summary: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&stats_file)?)?;
+    remove_file(&stats_file)?;
+    assert_eq!(summary["exitCode"], 0);
+    assert_eq!(summary["oomKilled"], false);
+    assert!(summary["memoryPeak"]
+        .as_u64()
+        .is_some_and(|m| m >= 32 << 20));
+    assert!(summary["pidsPeak"].as_u64().is_some_and(|p| p >= 1));
+    assert!(summary["wallTimeMs"].is_u64());
+    assert!(summary["cpuUserUsec"].is_u64());
+    assert!(summary["ioReadBytes"].is_u64());
+    Ok(())
+}
+
+#[test]
+fn device_filter() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let devices = std::env::temp_dir().join(format!("crabcan-devices-{}", std::process::id()));
+    create_dir_all(&devices)?;
+    let node = devices.join("loop");
+    let path = std::ffi::CString::new(node.to_str().unwrap())?;
+    assert_eq!(
+        unsafe { libc::mknod(path.as_ptr(), libc::S_IFBLK | 0o666, libc::makedev(7, 0)) },
+        0
+    );
+    std::fs::write(
+        devices.join("run.sh"),
+        ": < /d/loop && echo read ok\n: > /d/loop && echo write ok\n",
+    )?;
+    let mount = format!("{}:/d", devices.display());
+    let mapping = format!("{}:/d/loop:r", node.display());
+    let denied = run_container("devices", 0, "/bin/sh /d/run.sh", &["-a", &mount]);
+    let read_only = run_container(
+        "devices-r",
+        0,
+        "/bin/sh /d/run.sh",
+        &["-a", &mount, "--device", &mapping],
+    );
+    let invalid = run_container(
+        "devices-invalid",
+        0,
+        "/bin/true",
+        &["--device", devices.join("run.sh").to_str().unwrap()],
+    );
+    remove_file(devices.join("run.sh"))?;
+    remove_file(&node)?;
+    remove_dir(&devices)?;
+
+    // Only the default devices are allowed
+    let output = denied?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("read ok"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Operation not permitted"));
+
+    let output = read_only?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("read ok"), "{}", stdout);
+    assert!(!stdout.contains("write ok"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot create /d/loop"));
+
+    // Not a device node
+    let output = invalid?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("InvalidArgument: device"));
+    Ok(())
+}
+
+#[test]
+fn setup_error_reported_by_child() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let output = run_container("exec-error", 0, "/nonexistent", &[])?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(
+        stderr.contains("crabcan::ipc] Container setup failed at stage Exec"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("ENOENT"), "{}", stderr);
+    assert!(stderr.contains("ChildProcessError(2)"), "{}", stderr);
+
+    // Fails while the parent still waits on the child's setup
+    let output = run_container(
+        "mount-error",
+        0,
+        "/bin/true",
+        &["--device", "/dev/null:/bin/true/null"],
+    )?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(
+        stderr.contains("crabcan::ipc] Container setup failed at stage Mounts"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("MountError(2)"), "{}", stderr);
+    // Of the mkdir over a file, not of anything run after it
+    assert!(stderr.contains("EEXIST"), "{}", stderr);
+    Ok(())
+}
+
+#[test]
+fn environment_precedence() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let dir = std::env::temp_dir().join(format!("crabcan-env-{}", std::process::id()));
+    create_dir_all(&dir)?;
+    std::fs::write(dir.join("passwd"), "root:x:0:0:root:/root:/bin/sh\n")?;
+    std::fs::write(
+        dir.join("environment"),
+        "IMAGE=\"from image\"\nFILE=from-image\n",
+    )?;
+    std::fs::write(
+        dir.join("env"),
+        "# comment\n\nFILE=from-file\nCRABCAN_TEST_HOST=from-file\nCLI=from-file\n",
+    )?;
+    std::env::set_var("CRABCAN_TEST_HOST", "from-host");
+    let output = run_container(
+        "env",
+        0,
+        "",
+        &[
+            "-h",
+            "env-test",
+            "-a",
+            &format!("{}:/etc", dir.display()),
+            "--env-file",
+            dir.join("env").to_str().unwrap(),
+            "--env-host",
+            "CRABCAN_TEST_HOST",
+            "--env-host",
+            "CRABCAN_TEST_MISSING",
+            "-e",
+            "CLI=from cli",
+            "--",
+            "/usr/bin/env",
+        ],
+    );
+    let invalid = run_container(
+        "env-invalid",
+        0,
+        "/usr/bin/env",
+        &["--env-file", dir.join("passwd").to_str().unwrap()],
+    );
+    for file in ["passwd", "environment", "env"] {
+        remove_file(dir.join(file))?;
+    }
+    remove_dir(&dir)?;
+
+    let output = output?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let env: Vec<&str> = stdout.lines().collect();
+    for var in [
+        "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        "HOME=/root",
+        "HOSTNAME=env-test",
+        "IMAGE=from image",
+        "FILE=from-file",
+        "CRABCAN_TEST_HOST=from-host",
+        "CLI=from cli",
+    ] {
+        assert!(env.contains(&var), "{} not in {:?}", var, env);
+    }
+    assert!(!stdout.contains("CRABCAN_TEST_MISSING"));
+
+    let output = invalid?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("passwd:1: expected KEY=VALUE"));
+
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", "-e", "NOVALUE"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("InvalidArgument: env"));
+    Ok(())
+}
+
+#[test]
+fn user_workdir_and_umask() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let etc = std::env::temp_dir().join(format!("crabcan-users-{}", std::process::id()));
+    create_dir_all(&etc)?;
+    std::fs::write(
+        etc.join("passwd"),
+        "root:x:0:0:root:/root:/bin/sh\n\
+         app:x:1500:1500:app:/home/app:/bin/sh\n\
+         nobody:x:65534:65534:nobody:/nonexistent:/bin/sh\n",
+    )?;
+    std::fs::write(
+        etc.join("group"),
+        "root:x:0:\napp:x:1500:\nstaff:x:50:other,app\naudio:x:63:other\nnogroup:x:65534:\n",
+    )?;
+    let mount = format!("{}:/etc", etc.display());
+    let script = "/usr/bin/id; pwd; umask; echo $HOME";
+    let app = run_container(
+        "user-app",
+        0,
+        "",
+        &[
+            "-a", &mount, "--user", "app", "-w", "/usr", "--umask", "027", "--", "sh", "-c", script,
+        ],
+    );
+    let nobody = run_container(
+        "user-nobody",
+        0,
+        "",
+        &[
+            "-a",
+            &mount,
+            "--user",
+            "nobody:staff",
+            "--",
+            "sh",
+            "-c",
+            script,
+        ],
+    );
+    let missing = run_container(
+        "user-missing",
+        0,
+        "/bin/true",
+        &["-a", &mount, "--user", "missing"],
+    );
+    remove_file(etc.join("passwd"))?;
+    remove_file(etc.join("group"))?;
+    remove_dir(&etc)?;
+
+    // Supplementary groups come from the group file of the container
+    let output = app?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "uid=1500(app) gid=1500(app) groups=1500(app),50(staff)",
+            "/usr",
+            "0027",
+            "/home/app"
+        ]
+    );
+
+    let output = nobody?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.starts_with("uid=65534(nobody) gid=50(staff) groups=50(staff)\n/\n"),
+        "{}",
+        stdout
+    );
+
+    let output = missing?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("No user missing in /etc/passwd"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("NamespaceError(10)"), "{}", stderr);
+
+    let mut cmd = Command::cargo_bin("crabcan")?;
+    cmd.args(["-c", "/bin/sh", "-u", "0", "-m", "/tmp", "--umask", "999"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("InvalidArgument: umask"));
+    Ok(())
+}
+
+#[test]
+fn userns_range() -> TestResult {
+    if !is_root() {
+        return Ok(());
+    }
+    let uid_map = run_in_container(
+        "userns-range",
+        0,
+        "/bin/cat /proc/self/uid_map",
+        &["--userns-range", "100000:2000", "--user", "1999"],
+    )?;
+    assert_eq!(
+        uid_map.split_whitespace().collect::<Vec<_>>(),
+        ["0", "100000", "2000"]
+    );
+
+    // Above the default range
+    let output = run_container("userns-unmapped", 0, "/bin/true", &["--user", "70000"])?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("The uid 70000 is not mapped in the user namespace"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("NamespaceError(12)"), "{}", stderr);
+
+    for range in ["0:1000", "10000:0", "4294967295:2", "10000"] {
+        let mut cmd = Command::cargo_bin("crabcan")?;
+        cmd.args([
+            "-c",
+            "/bin/sh",
+            "-u",
+            "0",
+            "-m",
+            "/tmp",
+            "--userns-range",
+            range,
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("InvalidArgument: userns-range"));
+    }
+    Ok(())
+}